@@ -1,50 +1,128 @@
-#![allow(dead_code)]
+use crate::command::{set_config_vars, shell_argv};
+use crate::config::Config;
+use crate::error::{Error, DOCTOR_ERROR, GEN_ERROR, MANAGER_ERROR};
+use crate::gen::Generation;
+use crate::plan::Plan;
+use crate::privilege::{is_root, set_escalation};
+use crate::templates::ManagerTemplate;
 use crate::util::*;
-use crate::{cli::*, error::Error, genman::GenerationManager, globals::*, manager::Manager};
+use crate::{
+    cli::*,
+    doctor,
+    drift::Drift,
+    genman::GenerationManager,
+    globals::*,
+    manager::{hook_failures, set_oninstall, Manager},
+    order,
+};
 use clap::Parser;
-use std::{collections::HashMap, fs};
+use std::process::exit;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+};
 
 pub struct App {
-    managers: HashMap<String, Manager>,
+    config: Config,
+    managers: BTreeMap<String, Manager>,
     generation_manager: GenerationManager,
 }
 
+fn with_doctor_hint(err: Error) -> Error {
+    Error::new(&format!("{}, {}", err.msg, DOCTOR_HINT), err.code)
+}
+
 impl App {
     pub fn init() -> Self {
+        let cli = Cli::parse();
+        set_dry_run(cli.dry_run);
+        if is_root() {
+            eprintln!("{}", ROOT_WARNING);
+        }
         if !Self::is_already_setup() && yesnoprompt(ASK_FOR_SETUP_MSG) {
             terminate_on_error(Self::setup());
         }
+        let mutating = cli
+            .command
+            .as_ref()
+            .is_some_and(|command| command.is_mutating())
+            && !is_dry_run();
+        // Held until kaeru exits, read-only commands and dry runs never write.
+        let _lock = if mutating && Self::is_already_setup() {
+            let lock = terminate_on_error(acquire_lock(cli.wait));
+            terminate_on_error(Generation::recover_restore().map_err(Error::from));
+            Some(lock)
+        } else {
+            None
+        };
+        // Doctor has to run before anything is read, since that fails on the problems it reports.
+        if let Some(Commands::Doctor(doctor)) = &cli.command {
+            terminate_on_error(Self::check_corruption(doctor.fix && !is_dry_run()));
+            exit(0);
+        }
+        let config =
+            terminate_on_error(Self::load_config(_lock.is_some()).map_err(with_doctor_hint));
+        set_config_vars(config.vars.clone());
+        set_oninstall(config.package.oninstall());
+        set_escalation(config.manager.escalation());
         let mut app = Self {
-            managers: HashMap::new(),
-            generation_manager: GenerationManager::read(),
+            config,
+            managers: BTreeMap::new(),
+            generation_manager: terminate_on_error(
+                GenerationManager::read().map_err(with_doctor_hint),
+            ),
         };
-        app.read_data();
-        app.setup_cli();
-        app.generation_manager.save();
-        for (_, manager) in app.managers.iter() {
-            manager.save();
+        terminate_on_error(app.read_data().map_err(with_doctor_hint));
+        app.setup_cli(cli);
+        if mutating {
+            app.generation_manager.save();
+            for (_, manager) in app.managers.iter() {
+                manager.save();
+            }
+        }
+        if is_dry_run() {
+            Self::print_recorded_commands();
         }
         app
     }
 
-    fn read_data(&mut self) {
-        for manager in files_in_dir(&managers_dir(), ".toml").unwrap() {
-            self.managers.insert(
-                get_filename(&manager).unwrap().replace(".toml", ""),
-                Manager::new(manager),
-            );
+    fn print_recorded_commands() {
+        let commands = recorded_commands();
+        if commands.is_empty() {
+            println!("{}", DRY_RUN_NO_COMMANDS);
+            return;
+        }
+        println!("{}", DRY_RUN_COMMANDS);
+        for (i, command) in commands.iter().enumerate() {
+            println!("{:3}. {}", i + 1, command);
         }
     }
 
-    fn setup_cli(&mut self) {
-        let cli = Cli::parse();
+    fn read_data(&mut self) -> Result<(), Error> {
+        for manager in files_in_dir(&managers_dir(), MANAGER_FILE_EXT)? {
+            let manager = Manager::new(manager)?;
+            manager.check_config_vars(&self.config.vars)?;
+            self.managers.insert(manager_name(&manager.file), manager);
+        }
+        Ok(())
+    }
+
+    fn setup_cli(&mut self, cli: Cli) {
         if let Some(command) = cli.command {
             match command {
                 Commands::Gen(gen) => self.handle_generation(gen),
+                Commands::Manager(manager) => self.handle_manager(manager),
                 Commands::Install(install) => self.handle_install(install),
                 Commands::Remove(remove) => self.handle_remove(remove),
+                Commands::Group(group) => self.handle_group(group),
                 Commands::Sync(sync) => self.handle_sync(sync),
                 Commands::Upgrade(upgrade) => self.handle_upgrade(upgrade),
+                Commands::Status(status) => self.handle_status(status),
+                Commands::Plan(plan) => self.handle_plan(plan),
+                Commands::Apply(apply) => self.handle_apply(apply),
+                Commands::Bootstrap => self.handle_bootstrap(),
+                // Handled by init before any data is read.
+                Commands::Doctor(_) => {}
             }
         }
     }
@@ -56,11 +134,16 @@ impl App {
                 self.generation_manager.save();
             }
             GenerationCommand::Rollback(geninfo) => {
-                terminate_on_error(self.generation_manager.rollback(geninfo.genid));
+                let genid = terminate_on_error(self.generation_manager.resolve(&geninfo.genid));
+                terminate_on_error(self.generation_manager.rollback(genid));
                 self.generation_manager.save();
+                // The restored manager files replace the ones read at startup.
+                self.managers.clear();
+                terminate_on_error(self.read_data());
             }
             GenerationCommand::Remove(geninfo) => {
-                terminate_on_error(self.generation_manager.remove(geninfo.genid));
+                let genid = terminate_on_error(self.generation_manager.resolve(&geninfo.genid));
+                terminate_on_error(self.generation_manager.remove(genid));
                 self.generation_manager.save();
             }
             GenerationCommand::RemoveDuplicates => {
@@ -70,59 +153,395 @@ impl App {
             GenerationCommand::List => {
                 self.generation_manager.list_gens();
             }
+            GenerationCommand::Log(logdata) => {
+                if logdata.graph {
+                    self.generation_manager.log_graph();
+                } else {
+                    self.generation_manager.log();
+                }
+            }
             GenerationCommand::Apply(gendata) => {
-                self.generation_manager
-                    .apply_changes(gendata.genid.map(|gendata| gendata));
+                let genid = gendata
+                    .genid
+                    .map(|genid| terminate_on_error(self.generation_manager.resolve(&genid)));
+                let result = self.generation_manager.apply_changes(genid, &self.config);
+                // Failed post hooks still leave the generation applied.
+                self.generation_manager.save();
+                terminate_on_error(result);
             }
             GenerationCommand::Diff(diffdata) => {
+                let genid1 = terminate_on_error(self.generation_manager.resolve(&diffdata.genid1));
+                let genid2 = terminate_on_error(self.generation_manager.resolve(&diffdata.genid2));
+                let diffs = terminate_on_error(self.generation_manager.diff(genid1, genid2));
+                if diffdata.json {
+                    println!("{}", serde_json::to_string_pretty(&diffs).unwrap());
+                } else if diffs.is_empty() {
+                    println!("No changes between Gen {} and Gen {}", genid1, genid2);
+                } else {
+                    for diff in diffs {
+                        print!("{}", diff);
+                    }
+                }
+            }
+            GenerationCommand::Tag(tagdata) => {
+                let genid = match tagdata.genid {
+                    Some(genid) => terminate_on_error(self.generation_manager.resolve(&genid)),
+                    None => self.generation_manager.head(),
+                };
+                let moved_from =
+                    terminate_on_error(self.generation_manager.tag(tagdata.name.clone(), genid));
+                match moved_from {
+                    Some(prev) => {
+                        println!("Moved {} from Gen {} to Gen {}", tagdata.name, prev, genid)
+                    }
+                    None => println!("Tagged Gen {} as {}", genid, tagdata.name),
+                }
+                self.generation_manager.save();
+            }
+            GenerationCommand::Untag(tagdata) => {
+                terminate_on_error(self.generation_manager.untag(&tagdata.name));
+                self.generation_manager.save();
+            }
+            GenerationCommand::Pin(geninfo) => {
+                let genid = terminate_on_error(self.generation_manager.resolve(&geninfo.genid));
+                terminate_on_error(self.generation_manager.set_pinned(genid, true));
+                self.generation_manager.save();
+            }
+            GenerationCommand::Unpin(geninfo) => {
+                let genid = terminate_on_error(self.generation_manager.resolve(&geninfo.genid));
+                terminate_on_error(self.generation_manager.set_pinned(genid, false));
+                self.generation_manager.save();
+            }
+            GenerationCommand::Gc(gcdata) => {
+                let mut policy = self.config.gc.clone();
+                if gcdata.keep_last.is_some() {
+                    policy.keep_last = gcdata.keep_last;
+                }
+                if gcdata.keep_newer_than.is_some() {
+                    policy.keep_newer_than = gcdata.keep_newer_than;
+                }
+                let removed = terminate_on_error(self.generation_manager.gc(&policy));
+                let verb = if is_dry_run() {
+                    "Would remove"
+                } else {
+                    "Removed"
+                };
+                for genid in removed.iter() {
+                    println!("{} Gen {}", verb, genid);
+                }
+                if removed.is_empty() {
+                    println!("Nothing to remove");
+                }
+                self.generation_manager.save();
+            }
+        }
+    }
+    fn handle_manager(&mut self, command: ManagerCommand) {
+        match command {
+            ManagerCommand::Import(import) => {
+                if import.genesis && !self.generation_manager.is_empty() {
+                    terminate_on_error::<()>(Err(Error::new(ERR_GENS_EXIST, GEN_ERROR)));
+                }
+                let manager = terminate_on_error(self.get_manager_mut(&import.manager));
+                let installed = terminate_on_error(manager.installed());
+                if is_dry_run() {
+                    eprintln!("Skipping {}: {}", import.manager, DRY_RUN_NOT_QUERIED);
+                    return;
+                }
+                // Packages declared in an enabled group stay in their group.
+                let grouped: BTreeSet<_> = manager
+                    .packages()
+                    .difference(&manager.items)
+                    .cloned()
+                    .collect();
+                manager.items = installed.difference(&grouped).cloned().collect();
+                manager.save();
                 println!(
-                    "Diff between Gen {} and Gen {}",
-                    diffdata.genid1, diffdata.genid2
+                    "Imported {} packages into {}",
+                    manager.items.len(),
+                    import.manager
                 );
+                if import.genesis {
+                    terminate_on_error(
+                        self.generation_manager
+                            .genesis(IMPORT_GENESIS_MSG.to_string()),
+                    );
+                    self.generation_manager.save();
+                }
+            }
+            ManagerCommand::Add(add) => {
+                if self.managers.contains_key(&add.manager) {
+                    terminate_on_error::<()>(Err(Error::new(ERR_MANAGER_EXISTS, MANAGER_ERROR)));
+                }
+                let template_name = add.template.unwrap_or_else(|| add.manager.clone());
+                let template = terminate_on_error(ManagerTemplate::get(&template_name));
+                let file = format!("{}{}{}", managers_dir(), add.manager, MANAGER_FILE_EXT);
+                let manager = Manager::from_template(template, file);
+                manager.save();
+                println!("Created {} from template {}", manager.file, template_name);
+                self.managers.insert(add.manager, manager);
+            }
+            ManagerCommand::Templates => {
+                for (name, template) in terminate_on_error(ManagerTemplate::catalogue()) {
+                    let source = if template.builtin { "builtin" } else { "user" };
+                    println!("{:<10} {:<8} {}", name, source, template.installcmd);
+                }
             }
         }
     }
-    fn handle_install(&mut self, install: PkgData) {
-        self.managers
-            .get_mut(&install.manager)
-            .expect("The specified manager not found.")
-            .install(install.pkg_names)
-            .unwrap();
+    fn handle_install(&mut self, install: InstallArg) {
+        let manager = terminate_on_error(self.get_manager_mut(&install.manager));
+        let result = manager.install(install.pkg_names, install.group);
+        // Saved before reporting a failed hook, the packages are installed by then.
+        manager.save();
+        terminate_on_error(result);
+    }
+    fn handle_group(&mut self, command: GroupCommand) {
+        let (name, enabled) = match command {
+            GroupCommand::Enable(group) => (group.name, true),
+            GroupCommand::Disable(group) => (group.name, false),
+            GroupCommand::List => {
+                self.list_groups();
+                return;
+            }
+        };
+        let toggled: Vec<_> = self
+            .managers
+            .iter_mut()
+            .filter_map(|(manager_name, manager)| {
+                manager
+                    .set_group_enabled(&name, enabled)
+                    .then_some(manager_name.as_str())
+            })
+            .collect();
+        if toggled.is_empty() {
+            terminate_on_error::<()>(Err(Error::new(ERR_GROUP_NOT_FOUND, MANAGER_ERROR)));
+        }
+        println!(
+            "{} {} in {}, {}",
+            if enabled { "Enabled" } else { "Disabled" },
+            name,
+            toggled.join(", "),
+            GROUP_TOGGLED_HINT
+        );
+    }
+    fn list_groups(&self) {
+        let mut groups: BTreeMap<&String, Vec<String>> = BTreeMap::new();
+        for (manager_name, manager) in self.managers.iter() {
+            for (name, group) in manager.groups.iter() {
+                let state = if group.enabled { "" } else { ", disabled" };
+                groups.entry(name).or_default().push(format!(
+                    "{} ({} packages{})",
+                    manager_name,
+                    group.items.len(),
+                    state
+                ));
+            }
+        }
+        if groups.is_empty() {
+            println!("No groups, install into one with kaeru install --group");
+        }
+        for (name, managers) in groups {
+            println!("{}: {}", name, managers.join(", "));
+        }
     }
     fn handle_sync(&self, sync: SyncPkg) {
-        self.managers.get(&sync.manager).unwrap().sync().unwrap();
+        for manager in terminate_on_error(self.selected_managers(sync.manager)) {
+            terminate_on_error(manager.sync());
+        }
     }
     fn handle_upgrade(&self, upgrade: SyncPkg) {
+        for manager in terminate_on_error(self.selected_managers(upgrade.manager)) {
+            terminate_on_error(manager.upgrade());
+        }
+    }
+    /// The named manager, or every manager in call order.
+    fn selected_managers(&self, name: Option<String>) -> Result<Vec<&Manager>, Error> {
+        match name {
+            Some(name) => Ok(vec![self.get_manager(&name)?]),
+            None => self.ordered_managers(),
+        }
+    }
+    /// Every manager, each after the ones it requires and otherwise following call_order.
+    fn ordered_managers(&self) -> Result<Vec<&Manager>, Error> {
+        let requires = self
+            .managers
+            .iter()
+            .map(|(name, manager)| (name.clone(), manager.requires.clone()))
+            .collect();
+        let order = order::call_order(&requires, self.config.manager.call_order())?;
+        Ok(order.iter().map(|name| &self.managers[name]).collect())
+    }
+    fn handle_plan(&self, plan: PlanArg) {
+        let fingerprint = self.fingerprint();
+        let from = plan
+            .from
+            .map(|genid| terminate_on_error(self.generation_manager.resolve(&genid)));
+        let to = plan
+            .to
+            .map(|genid| terminate_on_error(self.generation_manager.resolve(&genid)));
+        let planned =
+            terminate_on_error(
+                self.generation_manager
+                    .plan(from, to, fingerprint, &self.config),
+            );
+        print!("{}", planned);
+        if !is_dry_run() {
+            terminate_on_error(planned.save(&plan.out));
+            println!("Plan written to {}", plan.out);
+        }
+    }
+    fn handle_apply(&mut self, apply: ApplyPlanArg) {
+        let plan = terminate_on_error(Plan::read(&apply.planfile));
+        terminate_on_error(plan.ensure_fresh(&self.fingerprint()));
+        print!("{}", plan);
+        let failed_hooks = terminate_on_error(plan.execute());
+        self.generation_manager.mark_applied(plan.to);
+        self.generation_manager.save();
+        terminate_on_error(hook_failures(failed_hooks));
+    }
+    fn handle_bootstrap(&mut self) {
+        for manager in terminate_on_error(self.ordered_managers()) {
+            let name = manager_name(&manager.file);
+            let Some(setup_cmd) = self.config.manager.setup_cmd(&name) else {
+                continue;
+            };
+            let check = shell_argv(manager.shell(), &self.config.manager.setup_check(&name));
+            // A dry run records the check and assumes the manager is missing.
+            if command_output(&check).is_ok() && !is_dry_run() {
+                println!("{} is already set up", name);
+                continue;
+            }
+            let setup_cmd = shell_argv(manager.shell(), setup_cmd);
+            terminate_on_error(run_command(&setup_cmd).map_err(Error::from));
+            if !is_dry_run() && command_output(&check).is_err() {
+                terminate_on_error::<()>(Err(Error::new(
+                    &format!("{}: {}", name, ERR_SETUP_INCOMPLETE),
+                    MANAGER_ERROR,
+                )));
+            }
+        }
+        let result = self.generation_manager.apply_from_scratch(&self.config);
+        self.generation_manager.save();
+        terminate_on_error(result);
+    }
+    /// Hash of the manager files and generations, as they would be saved.
+    fn fingerprint(&self) -> String {
+        let mut state = self.generation_manager.fingerprint();
+        for manager in self.managers.values() {
+            state.push_str(&manager.file);
+            state.push_str(&manager.as_toml());
+        }
+        content_hash(&state)
+    }
+    fn handle_status(&self, status: StatusArg) {
+        if is_dry_run() {
+            // The listcmds are recorded, but their output would be a made-up report.
+            let managers = match &status.manager {
+                Some(name) => vec![(name, terminate_on_error(self.get_manager(name)))],
+                None => self.managers.iter().collect(),
+            };
+            for (name, manager) in managers {
+                if status.manager.is_none() && !manager.has_listcmd() {
+                    eprintln!("Skipping {}: no listcmd", name);
+                    continue;
+                }
+                terminate_on_error(manager.installed());
+                eprintln!("Skipping {}: {}", name, DRY_RUN_NOT_QUERIED);
+            }
+            return;
+        }
+        let mut reports = Vec::new();
+        if let Some(name) = status.manager {
+            let manager = terminate_on_error(self.get_manager(&name));
+            reports.push(terminate_on_error(Drift::from_manager(manager)));
+        } else {
+            for (name, manager) in self.managers.iter() {
+                if !manager.has_listcmd() {
+                    eprintln!("Skipping {}: no listcmd", name);
+                    continue;
+                }
+                reports.push(terminate_on_error(Drift::from_manager(manager)));
+            }
+        }
+        if status.json {
+            println!("{}", serde_json::to_string_pretty(&reports).unwrap());
+        } else {
+            for report in reports {
+                print!("{}", report);
+            }
+        }
+    }
+    fn get_manager(&self, name: &str) -> Result<&Manager, Error> {
         self.managers
-            .get(&upgrade.manager)
-            .unwrap()
-            .upgrade()
-            .unwrap();
+            .get(name)
+            .ok_or_else(|| Error::new(ERR_MANAGER_NOT_FOUND, MANAGER_ERROR))
     }
-    fn handle_remove(&mut self, remove: PkgData) {
+    fn get_manager_mut(&mut self, name: &str) -> Result<&mut Manager, Error> {
         self.managers
-            .get_mut(&remove.manager)
-            .unwrap()
-            .remove(remove.pkg_names)
-            .unwrap();
+            .get_mut(name)
+            .ok_or_else(|| Error::new(ERR_MANAGER_NOT_FOUND, MANAGER_ERROR))
+    }
+    fn handle_remove(&mut self, remove: PkgData) {
+        let manager = terminate_on_error(self.get_manager_mut(&remove.manager));
+        let result = manager.remove(remove.pkg_names);
+        // Saved before reporting a failed hook, the packages are removed by then.
+        manager.save();
+        terminate_on_error(result);
     }
 
-    fn is_already_setup() -> bool {
-        if let Ok(res) = fs::exists(conf_file()) {
-            return res;
-        } else {
-            return false;
+    /// Reads config.toml, an old one is migrated on disk only while the lock is held.
+    fn load_config(locked: bool) -> Result<Config, Error> {
+        if !Self::is_already_setup() {
+            return Ok(Config::default());
+        }
+        let contents = get_contents_of(&conf_file())?;
+        if let Some(migrated) = Config::migrate(&contents).filter(|_| locked) {
+            overwrite_contents_of(&conf_file(), &migrated)?;
+            println!("{}", CONFIG_MIGRATED_MSG);
         }
+        Config::read(conf_file())
+    }
+
+    fn is_already_setup() -> bool {
+        fs::exists(conf_file()).unwrap_or(false)
     }
 
-    fn check_corruption(&self) -> Result<(), Error> {
-        todo!();
+    /// Reports every problem with kaeru's files, repairing the safe ones if `fix` is set.
+    fn check_corruption(fix: bool) -> Result<(), Error> {
+        let problems = doctor::diagnose();
+        let mut remaining = 0;
+        for problem in problems.iter() {
+            println!("{}", problem);
+            match (&problem.fix, fix) {
+                (Some(repair), true) => match repair.apply() {
+                    Ok(()) => println!("  fixed"),
+                    Err(err) => {
+                        println!("  fix failed: {}", err.msg);
+                        remaining += 1;
+                    }
+                },
+                _ => remaining += 1,
+            }
+        }
+        if problems.is_empty() {
+            println!("{}", DOCTOR_OK);
+        }
+        if remaining > 0 {
+            return Err(Error::new(
+                &format!("{} problem(s) need attention", remaining),
+                DOCTOR_ERROR,
+            ));
+        }
+        Ok(())
     }
 
     fn setup() -> Result<(), Error> {
         mkdir_if_not_exists(&conf_dir()).unwrap();
         mkdir_if_not_exists(&managers_dir()).unwrap();
         mkdir_if_not_exists(&gen_dir()).unwrap();
+        mkdir_if_not_exists(&objects_dir()).unwrap();
+        mkdir_if_not_exists(&templates_dir()).unwrap();
         create_file_with_contents(&conf_file(), DEFAULT_CONFIG);
         println!("{}", SETUP_COMPLETE);
         Ok(())
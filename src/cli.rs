@@ -1,24 +1,64 @@
+use crate::globals::DEFAULT_PLAN_FILE;
 use clap::{Args, Parser, Subcommand};
 
 #[derive(Parser)]
 pub struct Cli {
+    /// Print the commands that would run instead of running them, nothing is written to disk
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+    /// Wait for another running kaeru to finish instead of failing
+    #[arg(long, global = true)]
+    pub wait: bool,
     #[command(subcommand)]
     pub command: Option<Commands>,
 }
 
+impl Commands {
+    /// Whether the command changes kaeru's state or the system, and so needs the lock.
+    pub fn is_mutating(&self) -> bool {
+        match self {
+            Commands::Gen(gen) => !matches!(
+                gen,
+                GenerationCommand::List | GenerationCommand::Log(_) | GenerationCommand::Diff(_)
+            ),
+            Commands::Manager(manager) => !matches!(manager, ManagerCommand::Templates),
+            Commands::Group(group) => !matches!(group, GroupCommand::List),
+            Commands::Plan(_) | Commands::Status(_) => false,
+            Commands::Doctor(doctor) => doctor.fix,
+            _ => true,
+        }
+    }
+}
+
 #[derive(Subcommand)]
 pub enum Commands {
     /// Generation-related commands
     #[command(subcommand)]
     Gen(GenerationCommand),
+    /// Manager-related commands
+    #[command(subcommand)]
+    Manager(ManagerCommand),
+    /// Package group commands
+    #[command(subcommand)]
+    Group(GroupCommand),
     /// Install a package with the specified manager
-    Install(PkgData),
+    Install(InstallArg),
     /// Removes a package with the specified manager
     Remove(PkgData),
-    /// Sync the database of a manager
+    /// Sync the database of a manager, or of every manager in call order
     Sync(SyncPkg),
-    /// Upgrade manager
+    /// Upgrade a manager, or every manager in call order
     Upgrade(SyncPkg),
+    /// Write the commands needed to reach a generation to a plan file
+    Plan(PlanArg),
+    /// Execute a plan file made by kaeru plan
+    Apply(ApplyPlanArg),
+    /// Compare declared packages against what is actually installed
+    Status(StatusArg),
+    /// Set up missing managers in call order, then install the latest generation
+    Bootstrap,
+    /// Check kaeru's directories, config, manager files and generations for problems
+    Doctor(DoctorArg),
 }
 
 #[derive(Subcommand)]
@@ -27,34 +67,144 @@ pub enum GenerationCommand {
     Remove(GenerationId),
     /// Remove duplicate generations
     RemoveDuplicates,
-    /// Rollback to a generation id.
+    /// Rollback to a generation id, the next commit branches off it
     Rollback(GenerationId),
     /// List all generations
     List,
+    /// Show the history of the head generation
+    Log(LogArg),
     /// Make the changes take effect, this starts a new generation
     Commit(GenerationMessage),
     /// Apply any leftover changes
     Apply(ApplyArg),
     /// Get difference between 2 generations
     Diff(DiffData),
+    /// Tag a generation, moving the tag if it is already in use
+    Tag(TagArg),
+    /// Delete a tag
+    Untag(TagName),
+    /// Protect a generation from kaeru gen gc
+    Pin(GenerationId),
+    /// Let kaeru gen gc remove a pinned generation again
+    Unpin(GenerationId),
+    /// Remove old generations according to the [gc] retention policy in config.toml
+    Gc(GcArg),
+}
+
+#[derive(Subcommand)]
+pub enum ManagerCommand {
+    /// Replace a manager's items with the packages installed on this system
+    Import(ImportArg),
+    /// Create a manager file from a template
+    Add(AddManagerArg),
+    /// List the available manager templates
+    Templates,
+}
+
+#[derive(Subcommand)]
+pub enum GroupCommand {
+    /// Enable a group in every manager that has it, its packages install on the next apply
+    Enable(GroupName),
+    /// Disable a group in every manager that has it, its packages are removed on the next apply
+    Disable(GroupName),
+    /// List the groups of every manager
+    List,
+}
+
+#[derive(Args)]
+pub struct GroupName {
+    /// Name of the group
+    pub name: String,
+}
+
+#[derive(Args)]
+pub struct AddManagerArg {
+    /// Name of the new manager
+    pub manager: String,
+    /// Template to use, defaults to the manager name
+    #[arg(long)]
+    pub template: Option<String>,
+}
+
+#[derive(Args)]
+pub struct ImportArg {
+    /// Manager to import, it needs a listcmd
+    pub manager: String,
+    /// Record the imported state as the genesis generation
+    #[arg(long)]
+    pub genesis: bool,
 }
 
 #[derive(Args)]
 pub struct DiffData {
-    /// Generation ID of gens you wanna compare, find it using kaeru gen list
-    pub genid1: usize,
-    pub genid2: usize,
+    /// Generation ID or tag of gens you wanna compare, find it using kaeru gen list
+    pub genid1: String,
+    pub genid2: String,
+    /// Print the diff as JSON instead of text
+    #[arg(long)]
+    pub json: bool,
 }
 
 #[derive(Args)]
 pub struct ApplyArg {
-    pub genid: Option<usize>,
+    /// Generation ID or tag to diff against, defaults to the currently applied one
+    pub genid: Option<String>,
+}
+
+#[derive(Args)]
+pub struct PlanArg {
+    /// Generation ID or tag to reach, defaults to the latest one
+    #[arg(long)]
+    pub to: Option<String>,
+    /// Generation ID or tag to start from, defaults to the currently applied one
+    #[arg(long)]
+    pub from: Option<String>,
+    /// Where to write the plan
+    #[arg(long, short, default_value = DEFAULT_PLAN_FILE)]
+    pub out: String,
+}
+
+#[derive(Args)]
+pub struct ApplyPlanArg {
+    /// Plan file made by kaeru plan
+    pub planfile: String,
 }
 
 #[derive(Args)]
 pub struct GenerationId {
-    /// Generation ID, find it using kaeru gen list
-    pub genid: usize,
+    /// Generation ID or tag, find it using kaeru gen list
+    pub genid: String,
+}
+
+#[derive(Args)]
+pub struct LogArg {
+    /// Show every generation as a tree, including abandoned branches
+    #[arg(long)]
+    pub graph: bool,
+}
+
+#[derive(Args)]
+pub struct GcArg {
+    /// Keep the last N generations, overrides keep_last from config.toml
+    #[arg(long)]
+    pub keep_last: Option<usize>,
+    /// Keep generations newer than this, e.g. 30d, overrides keep_newer_than from config.toml
+    #[arg(long)]
+    pub keep_newer_than: Option<String>,
+}
+
+#[derive(Args)]
+pub struct TagArg {
+    /// Name of the tag, can be used anywhere a generation ID is accepted
+    pub name: String,
+    /// Generation ID or tag to tag, defaults to the head generation
+    pub genid: Option<String>,
+}
+
+#[derive(Args)]
+pub struct TagName {
+    /// Name of the tag
+    pub name: String,
 }
 
 #[derive(Args)]
@@ -72,7 +222,34 @@ pub struct PkgData {
 }
 
 #[derive(Args)]
-pub struct SyncPkg {
-    /// Manager to sync
+pub struct InstallArg {
+    /// With which manager to install
     pub manager: String,
+    /// Name of package to install
+    pub pkg_names: Vec<String>,
+    /// Add the packages to this group instead of the manager's items
+    #[arg(long)]
+    pub group: Option<String>,
+}
+
+#[derive(Args)]
+pub struct SyncPkg {
+    /// Manager to use, all managers are used otherwise
+    pub manager: Option<String>,
+}
+
+#[derive(Args)]
+pub struct StatusArg {
+    /// Only check this manager, checks every manager with a listcmd otherwise
+    pub manager: Option<String>,
+    /// Print the report as JSON instead of text
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Args)]
+pub struct DoctorArg {
+    /// Repair the problems that can be fixed safely
+    #[arg(long)]
+    pub fix: bool,
 }
@@ -0,0 +1,680 @@
+//! Manager commands and the placeholders they can use:
+//!
+//! - `{{packages}}` or `:#?`, every package as its own argument
+//! - `{{packages_quoted}}`, every package in a single argument, separated by spaces
+//! - `{{package}}` and `{{version}}`, a package and its version from `[versions]`,
+//!   a command using them runs once per package
+//! - `{{env.NAME}}`, an environment variable, empty if unset
+//! - `{{config.NAME}}`, a variable from `[vars]` in config.toml
+//! - `{{arch}}` and `{{distro}}`, e.g. x86_64 and the ID from /etc/os-release
+//! - `{{#if NAME}}...{{/if}}`, kept only when the placeholder NAME is set and isn't
+//!   "false" or "0", an argv argument left empty by it is dropped
+//!
+//! Packages and versions are quoted in shell strings, other values are put in as they are.
+//! `{{{{` is a literal `{{`, and braces around anything that isn't a name, like the Go
+//! template `{{.Repository}}`, are kept as they are.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::sync::OnceLock;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, MANAGER_ERROR};
+use crate::globals::{
+    ERR_CONFIG_VAR, ERR_EMPTY_COMMAND, ERR_PACKAGES_ARG, ERR_PACKAGE_NAME, ERR_PER_PACKAGE,
+    PACKAGES_PLACEHOLDER,
+};
+use crate::util::get_contents_of;
+
+static CONFIG_VARS: OnceLock<BTreeMap<String, String>> = OnceLock::new();
+
+/// Sets the values of `{{config.NAME}}`, read from config.toml at startup.
+pub fn set_config_vars(vars: BTreeMap<String, String>) {
+    let _ = CONFIG_VARS.set(vars);
+}
+
+/// A command from a manager file. Shell strings run under the manager's POSIX shell with
+/// the packages quoted, argv arrays run directly with every package as its own argument.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged, try_from = "RawCommandLine")]
+pub enum CommandLine {
+    Shell(String),
+    Argv(Vec<String>),
+}
+
+/// A command as written, checked for unknown placeholders before it becomes a `CommandLine`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawCommandLine {
+    Shell(String),
+    Argv(Vec<String>),
+}
+
+impl TryFrom<RawCommandLine> for CommandLine {
+    type Error = String;
+
+    fn try_from(raw: RawCommandLine) -> Result<Self, String> {
+        let cmd = match raw {
+            RawCommandLine::Shell(script) => CommandLine::Shell(script),
+            RawCommandLine::Argv(args) => CommandLine::Argv(args),
+        };
+        cmd.parse().map_err(|err| err.msg)?;
+        Ok(cmd)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Placeholder {
+    Packages,
+    PackagesQuoted,
+    Package,
+    Version,
+    Env(String),
+    Config(String),
+    Arch,
+    Distro,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Text(String),
+    Value(Placeholder),
+    If(Placeholder, Vec<Segment>),
+}
+
+/// One run of a command, with the packages it covers.
+pub struct Invocation {
+    pub packages: Vec<String>,
+    pub argv: Vec<String>,
+}
+
+/// What the placeholders of one invocation stand for.
+struct Values<'a> {
+    shell: bool,
+    packages: &'a [String],
+    package: Option<&'a str>,
+    version: Option<&'a str>,
+}
+
+impl Placeholder {
+    fn parse(name: &str) -> Result<Self, Error> {
+        let placeholder = match name {
+            "packages" => Placeholder::Packages,
+            "packages_quoted" => Placeholder::PackagesQuoted,
+            "package" => Placeholder::Package,
+            "version" => Placeholder::Version,
+            "arch" => Placeholder::Arch,
+            "distro" => Placeholder::Distro,
+            _ => match name.split_once('.') {
+                Some(("env", var)) if !var.is_empty() => Placeholder::Env(var.to_string()),
+                Some(("config", var)) if !var.is_empty() => Placeholder::Config(var.to_string()),
+                _ => {
+                    return Err(template_error(&format!(
+                        "unknown placeholder {{{{{}}}}}",
+                        name
+                    )))
+                }
+            },
+        };
+        Ok(placeholder)
+    }
+
+    fn is_per_package(&self) -> bool {
+        matches!(self, Placeholder::Package | Placeholder::Version)
+    }
+
+    /// The value, None if it isn't set. Config variables that aren't set are an error.
+    fn value(&self, values: &Values) -> Result<Option<String>, Error> {
+        let value =
+            match self {
+                Placeholder::Packages => {
+                    let quoted: Vec<_> = values.packages.iter().map(|pkg| quote(pkg)).collect();
+                    Some(quoted.join(" "))
+                }
+                Placeholder::PackagesQuoted => Some(values.packages.join(" ")).map(|list| {
+                    if values.shell {
+                        quote(&list)
+                    } else {
+                        list
+                    }
+                }),
+                Placeholder::Package => values.package.map(|pkg| Self::package_value(pkg, values)),
+                Placeholder::Version => values.version.map(|ver| Self::package_value(ver, values)),
+                Placeholder::Env(var) => std::env::var(var).ok(),
+                Placeholder::Config(var) => {
+                    let vars = CONFIG_VARS.get_or_init(BTreeMap::new);
+                    Some(
+                        vars.get(var)
+                            .cloned()
+                            .ok_or_else(|| unset_config_var(var))?,
+                    )
+                }
+                Placeholder::Arch => Some(std::env::consts::ARCH.to_string()),
+                Placeholder::Distro => Some(distro()),
+            };
+        Ok(value.filter(|value| !value.is_empty()))
+    }
+
+    fn package_value(value: &str, values: &Values) -> String {
+        if values.shell {
+            quote(value)
+        } else {
+            value.to_string()
+        }
+    }
+
+    /// Whether a `{{#if}}` on this placeholder keeps its section.
+    fn is_set(&self, values: &Values) -> bool {
+        match self {
+            Placeholder::Config(var) => CONFIG_VARS
+                .get_or_init(BTreeMap::new)
+                .get(var)
+                .is_some_and(|value| is_truthy(value)),
+            _ => self
+                .value(values)
+                .ok()
+                .flatten()
+                .is_some_and(|value| is_truthy(&value)),
+        }
+    }
+}
+
+fn is_truthy(value: &str) -> bool {
+    !matches!(value, "" | "0" | "false")
+}
+
+fn quote(value: &str) -> String {
+    shlex::try_quote(value).unwrap().into_owned()
+}
+
+fn template_error(msg: &str) -> Error {
+    Error::new(msg, MANAGER_ERROR)
+}
+
+fn unset_config_var(var: &str) -> Error {
+    Error::new(
+        &format!("{{{{config.{}}}}}: {}", var, ERR_CONFIG_VAR),
+        MANAGER_ERROR,
+    )
+}
+
+/// Whether a tag is a placeholder name, e.g. `packages` or `env.HOME`.
+fn is_name(tag: &str) -> bool {
+    tag.starts_with(|c: char| c.is_ascii_alphabetic())
+        && tag
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/// Splits a template into text, placeholders and `{{#if}}` sections.
+fn parse_template(template: &str) -> Result<Vec<Segment>, Error> {
+    let template = template.replace(PACKAGES_PLACEHOLDER, "{{packages}}");
+    let mut stack: Vec<(Placeholder, Vec<Segment>)> = Vec::new();
+    let mut segments = Vec::new();
+    let mut text = String::new();
+    let mut rest = template.as_str();
+    while let Some(start) = rest.find("{{") {
+        text.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("{{{{") {
+            text.push_str("{{");
+            rest = after;
+            continue;
+        }
+        let Some(end) = rest.find("}}") else {
+            break;
+        };
+        let tag = rest[2..end].trim();
+        let segment = if let Some(name) = tag.strip_prefix("#if ") {
+            Some(Placeholder::parse(name.trim())?)
+        } else if tag == "/if" || is_name(tag) {
+            None
+        } else {
+            // Not ours, e.g. a Go template.
+            text.push_str(&rest[..end + 2]);
+            rest = &rest[end + 2..];
+            continue;
+        };
+        rest = &rest[end + 2..];
+        if !text.is_empty() {
+            segments.push(Segment::Text(std::mem::take(&mut text)));
+        }
+        if let Some(placeholder) = segment {
+            stack.push((placeholder, std::mem::take(&mut segments)));
+        } else if tag == "/if" {
+            let (placeholder, outer) = stack
+                .pop()
+                .ok_or_else(|| template_error("{{/if}} without {{#if}} in command"))?;
+            let section = std::mem::replace(&mut segments, outer);
+            segments.push(Segment::If(placeholder, section));
+        } else {
+            segments.push(Segment::Value(Placeholder::parse(tag)?));
+        }
+    }
+    text.push_str(rest);
+    if !text.is_empty() {
+        segments.push(Segment::Text(text));
+    }
+    if !stack.is_empty() {
+        return Err(template_error("{{#if}} without {{/if}} in command"));
+    }
+    Ok(segments)
+}
+
+/// Adds the config variables the segments can't render without, those used only inside
+/// an `{{#if}}` on themselves may be unset.
+fn required_config_vars(segments: &[Segment], guards: &[&str], vars: &mut BTreeSet<String>) {
+    for segment in segments {
+        match segment {
+            Segment::Value(Placeholder::Config(var)) if !guards.contains(&var.as_str()) => {
+                vars.insert(var.clone());
+            }
+            Segment::If(placeholder, section) => {
+                let mut guards = guards.to_vec();
+                if let Placeholder::Config(var) = placeholder {
+                    guards.push(var);
+                }
+                required_config_vars(section, &guards, vars);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn uses(segments: &[Segment], check: &dyn Fn(&Placeholder) -> bool) -> bool {
+    segments.iter().any(|segment| match segment {
+        Segment::Text(_) => false,
+        Segment::Value(placeholder) => check(placeholder),
+        Segment::If(placeholder, section) => check(placeholder) || uses(section, check),
+    })
+}
+
+fn render(segments: &[Segment], values: &Values) -> Result<String, Error> {
+    let mut rendered = String::new();
+    for segment in segments {
+        match segment {
+            Segment::Text(text) => rendered.push_str(text),
+            Segment::Value(placeholder) => {
+                rendered.push_str(&placeholder.value(values)?.unwrap_or_default())
+            }
+            Segment::If(placeholder, section) => {
+                if placeholder.is_set(values) {
+                    rendered.push_str(&render(section, values)?);
+                }
+            }
+        }
+    }
+    Ok(rendered)
+}
+
+/// A parsed command, argv arguments are parsed one by one.
+enum Parsed {
+    Shell(Vec<Segment>),
+    Argv(Vec<Vec<Segment>>),
+}
+
+impl Parsed {
+    fn uses(&self, check: &dyn Fn(&Placeholder) -> bool) -> bool {
+        match self {
+            Parsed::Shell(segments) => uses(segments, check),
+            Parsed::Argv(args) => args.iter().any(|arg| uses(arg, check)),
+        }
+    }
+}
+
+fn is_whole_packages_arg(arg: &[Segment]) -> bool {
+    matches!(arg, [Segment::Value(Placeholder::Packages)])
+}
+
+impl CommandLine {
+    fn parse(&self) -> Result<Parsed, Error> {
+        let parsed = match self {
+            CommandLine::Shell(script) => Parsed::Shell(parse_template(script)?),
+            CommandLine::Argv(args) if args.is_empty() => {
+                return Err(Error::new(ERR_EMPTY_COMMAND, MANAGER_ERROR))
+            }
+            CommandLine::Argv(args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| parse_template(arg))
+                    .collect::<Result<Vec<_>, _>>()?;
+                // Only a whole argument can stand for several packages.
+                if args.iter().any(|arg| {
+                    !is_whole_packages_arg(arg)
+                        && uses(arg, &|placeholder| *placeholder == Placeholder::Packages)
+                }) {
+                    return Err(template_error(ERR_PACKAGES_ARG));
+                }
+                Parsed::Argv(args)
+            }
+        };
+        if parsed.uses(&Placeholder::is_per_package)
+            && parsed.uses(&|placeholder| {
+                matches!(
+                    placeholder,
+                    Placeholder::Packages | Placeholder::PackagesQuoted
+                )
+            })
+        {
+            return Err(template_error(ERR_PER_PACKAGE));
+        }
+        Ok(parsed)
+    }
+
+    /// Errors if the command uses a `{{config.NAME}}` that isn't set in `vars`.
+    pub fn check_config_vars(&self, vars: &BTreeMap<String, String>) -> Result<(), Error> {
+        let mut required = BTreeSet::new();
+        match self.parse()? {
+            Parsed::Shell(segments) => required_config_vars(&segments, &[], &mut required),
+            Parsed::Argv(args) => {
+                for arg in args.iter() {
+                    required_config_vars(arg, &[], &mut required);
+                }
+            }
+        }
+        match required.iter().find(|var| !vars.contains_key(*var)) {
+            Some(var) => Err(unset_config_var(var)),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether the command runs once per package instead of once for all of them.
+    pub fn is_per_package(&self) -> bool {
+        self.parse()
+            .is_ok_and(|parsed| parsed.uses(&Placeholder::is_per_package))
+    }
+
+    /// The invocations to run, one for all packages or one per package if the command
+    /// uses `{{package}}`.
+    pub fn invocations(
+        &self,
+        shell: &str,
+        packages: &[String],
+        versions: &BTreeMap<String, String>,
+    ) -> Result<Vec<Invocation>, Error> {
+        for pkg in packages {
+            validate_package(pkg)?;
+        }
+        let parsed = self.parse()?;
+        let is_shell = matches!(parsed, Parsed::Shell(_));
+        let values: Vec<Values> = if parsed.uses(&Placeholder::is_per_package) {
+            packages
+                .iter()
+                .map(|pkg| Values {
+                    shell: is_shell,
+                    packages: std::slice::from_ref(pkg),
+                    package: Some(pkg),
+                    version: versions.get(pkg).map(String::as_str),
+                })
+                .collect()
+        } else {
+            vec![Values {
+                shell: is_shell,
+                packages,
+                package: None,
+                version: None,
+            }]
+        };
+        values
+            .iter()
+            .map(|values| {
+                let argv = match &parsed {
+                    Parsed::Shell(segments) => shell_argv(shell, &render(segments, values)?),
+                    Parsed::Argv(args) => render_argv(args, values)?,
+                };
+                Ok(Invocation {
+                    packages: values.packages.to_vec(),
+                    argv,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Renders argv arguments, `{{packages}}` splices in every package and an argument left
+/// empty by a `{{#if}}` is dropped.
+fn render_argv(args: &[Vec<Segment>], values: &Values) -> Result<Vec<String>, Error> {
+    let mut argv = Vec::new();
+    for arg in args {
+        if is_whole_packages_arg(arg) {
+            argv.extend(values.packages.iter().cloned());
+            continue;
+        }
+        let rendered = render(arg, values)?;
+        let conditional = arg.iter().any(|seg| matches!(seg, Segment::If(..)));
+        if !(rendered.is_empty() && conditional) {
+            argv.push(rendered);
+        }
+    }
+    Ok(argv)
+}
+
+impl fmt::Display for CommandLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandLine::Shell(script) => write!(f, "{}", script),
+            CommandLine::Argv(args) => write!(f, "{}", display_argv(args)),
+        }
+    }
+}
+
+/// The ID from /etc/os-release, or the OS name where there is none.
+fn distro() -> String {
+    let os_release = get_contents_of("/etc/os-release").unwrap_or_default();
+    os_release
+        .lines()
+        .find_map(|line| line.strip_prefix("ID="))
+        .map(|id| id.trim_matches('"').to_string())
+        .unwrap_or_else(|| std::env::consts::OS.to_string())
+}
+
+/// Runs a script with `shell -c`.
+pub fn shell_argv(shell: &str, script: &str) -> Vec<String> {
+    vec![shell.to_string(), "-c".to_string(), script.to_string()]
+}
+
+/// The arguments quoted the way a POSIX shell would need them.
+pub fn display_argv(argv: &[String]) -> String {
+    shlex::try_join(argv.iter().map(String::as_str)).unwrap_or_else(|_| argv.join(" "))
+}
+
+/// Rejects package names that would be read as options or can't be passed safely.
+pub fn validate_package(name: &str) -> Result<(), Error> {
+    if name.is_empty() || name.starts_with('-') || name.chars().any(char::is_control) {
+        return Err(Error::new(
+            &format!("{:?}: {}", name, ERR_PACKAGE_NAME),
+            MANAGER_ERROR,
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(text: &str) -> Segment {
+        Segment::Text(text.to_string())
+    }
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn argv(args: &[&str]) -> CommandLine {
+        CommandLine::Argv(strings(args))
+    }
+
+    fn values(packages: &[String]) -> Values<'_> {
+        Values {
+            shell: false,
+            packages,
+            package: None,
+            version: None,
+        }
+    }
+
+    #[test]
+    fn parses_placeholders_and_text() {
+        assert_eq!(
+            parse_template("apt install :#? -t {{ config.suite }}").unwrap(),
+            [
+                text("apt install "),
+                Segment::Value(Placeholder::Packages),
+                text(" -t "),
+                Segment::Value(Placeholder::Config("suite".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_nested_if_sections() {
+        assert_eq!(
+            parse_template("a{{#if env.X}}b{{#if arch}}c{{/if}}{{/if}}d").unwrap(),
+            [
+                text("a"),
+                Segment::If(
+                    Placeholder::Env("X".to_string()),
+                    vec![text("b"), Segment::If(Placeholder::Arch, vec![text("c")])]
+                ),
+                text("d"),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_unbalanced_if_sections() {
+        assert!(parse_template("a{{#if arch}}b").is_err());
+        assert!(parse_template("a{{#if arch}}b{{#if distro}}c{{/if}}").is_err());
+        assert!(parse_template("a{{/if}}").is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_names() {
+        assert!(parse_template("{{pakages}}").is_err());
+        assert!(parse_template("{{env.}}").is_err());
+        assert!(parse_template("{{#if .x}}{{/if}}").is_err());
+    }
+
+    #[test]
+    fn keeps_other_braces_as_text() {
+        let go = "docker images --format '{{.Repository}}:{{ .Tag }}'";
+        assert_eq!(parse_template(go).unwrap(), [text(go)]);
+        assert_eq!(parse_template("echo {{").unwrap(), [text("echo {{")]);
+        assert_eq!(
+            parse_template("awk '{{{{print}}' {{packages}}").unwrap(),
+            [
+                text("awk '{{print}}' "),
+                Segment::Value(Placeholder::Packages)
+            ]
+        );
+    }
+
+    #[test]
+    fn render_argv_splices_packages_and_drops_empty_conditionals() {
+        let args: Vec<_> = ["npm", "{{#if config.unset}}--global{{/if}}", "", ":#?"]
+            .iter()
+            .map(|arg| parse_template(arg).unwrap())
+            .collect();
+        let packages = strings(&["a", "b c"]);
+        assert_eq!(
+            render_argv(&args, &values(&packages)).unwrap(),
+            ["npm", "", "a", "b c"]
+        );
+    }
+
+    #[test]
+    fn render_argv_keeps_packages_quoted_in_one_argument() {
+        let args = vec![parse_template("--only={{packages_quoted}}").unwrap()];
+        let packages = strings(&["a", "b"]);
+        assert_eq!(
+            render_argv(&args, &values(&packages)).unwrap(),
+            ["--only=a b"]
+        );
+    }
+
+    #[test]
+    fn packages_must_be_a_whole_argv_argument() {
+        let err = argv(&["echo", "--pkgs={{packages}}"])
+            .parse()
+            .err()
+            .unwrap();
+        assert_eq!(err.msg, ERR_PACKAGES_ARG);
+        assert!(argv(&["echo", "--pkgs=:#?"]).parse().is_err());
+        assert!(CommandLine::Shell("echo --pkgs={{packages}}".to_string())
+            .parse()
+            .is_ok());
+    }
+
+    #[test]
+    fn shell_invocations_quote_packages() {
+        let cmd = CommandLine::Shell("pkg add :#? {{packages_quoted}}".to_string());
+        let invocations = cmd
+            .invocations("/bin/sh", &strings(&["a b", "c"]), &BTreeMap::new())
+            .unwrap();
+        assert_eq!(invocations.len(), 1);
+        assert_eq!(
+            invocations[0].argv,
+            ["/bin/sh", "-c", "pkg add 'a b' c 'a b c'"]
+        );
+    }
+
+    #[test]
+    fn argv_invocations_pass_packages_as_arguments() {
+        let cmd = argv(&["pkg", "add", ":#?"]);
+        let invocations = cmd
+            .invocations("/bin/sh", &strings(&["a b", "$(c)"]), &BTreeMap::new())
+            .unwrap();
+        assert_eq!(invocations[0].argv, ["pkg", "add", "a b", "$(c)"]);
+        assert_eq!(invocations[0].packages, ["a b", "$(c)"]);
+    }
+
+    #[test]
+    fn per_package_placeholders_run_once_per_package() {
+        let versions = BTreeMap::from([("a".to_string(), "1.0".to_string())]);
+        let cmd = argv(&["add", "{{package}}{{#if version}}@{{version}}{{/if}}"]);
+        let invocations = cmd
+            .invocations("/bin/sh", &strings(&["a", "b"]), &versions)
+            .unwrap();
+        let argvs: Vec<_> = invocations.iter().map(|inv| inv.argv.clone()).collect();
+        assert_eq!(argvs, [strings(&["add", "a@1.0"]), strings(&["add", "b"])]);
+        assert_eq!(invocations[1].packages, ["b"]);
+
+        let shell = CommandLine::Shell("add {{package}}".to_string());
+        let invocations = shell
+            .invocations("/bin/sh", &strings(&["it's"]), &versions)
+            .unwrap();
+        assert_eq!(invocations[0].argv[2], r#"add "it's""#);
+        assert!(shell.is_per_package());
+    }
+
+    #[test]
+    fn per_package_and_packages_placeholders_dont_mix() {
+        let err = argv(&["add", "{{package}}", ":#?"]).parse().err().unwrap();
+        assert_eq!(err.msg, ERR_PER_PACKAGE);
+    }
+
+    #[test]
+    fn invalid_package_names_are_rejected() {
+        let cmd = argv(&["rm", ":#?"]);
+        for name in ["-rf", "", "a\nb"] {
+            assert!(cmd
+                .invocations("/bin/sh", &strings(&[name]), &BTreeMap::new())
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn config_vars_are_required_unless_guarded_by_themselves() {
+        let vars = BTreeMap::from([("set".to_string(), "x".to_string())]);
+        let check = |cmd: &str| CommandLine::Shell(cmd.to_string()).check_config_vars(&vars);
+        assert!(check("a {{config.set}}").is_ok());
+        assert!(check("a {{#if config.mirror}}--mirror {{config.mirror}}{{/if}}").is_ok());
+        assert!(check("a {{config.mirror}}").is_err());
+        assert!(check("a {{#if config.set}}{{config.mirror}}{{/if}}").is_err());
+        assert!(argv(&["a", "{{config.mirror}}"])
+            .check_config_vars(&vars)
+            .is_err());
+    }
+}
@@ -1,28 +1,219 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
-use crate::util::get_contents_of;
-use serde::{Deserialize, Serialize};
-use toml::{de::Error, from_str};
+use crate::command::CommandLine;
+use crate::error::{Error, CONFIG_ERROR};
+use crate::globals::ERR_INVALID_DURATION;
+use crate::privilege::Escalation;
+use crate::util::{get_contents_of, parse_duration};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use toml::{from_str, Table};
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Tables the default config used to be written with, and what they are called now.
+const RENAMED_TABLES: [(&str, &str); 2] = [("managers", "manager"), ("packages", "package")];
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
-    manager: Option<ManagerConfig>,
-    package: Option<PackageConfig>,
+    #[serde(default)]
+    pub manager: ManagerConfig,
+    #[serde(default)]
+    pub package: PackageConfig,
+    #[serde(default)]
+    pub gc: GcConfig,
+    /// Values for `{{config.NAME}}` in manager commands.
+    #[serde(default)]
+    pub vars: BTreeMap<String, String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
 pub struct ManagerConfig {
-    call_order: Option<Vec<String>>,
-    setup_cmds: Option<HashMap<String, String>>,
+    pub call_order: Option<Vec<String>>,
+    /// Commands that install a manager itself, run by `kaeru bootstrap`.
+    pub setup_cmds: Option<HashMap<String, String>>,
+    /// Commands that succeed once a manager is usable, `command -v <manager>` by default.
+    pub setup_checks: Option<HashMap<String, String>>,
+    /// Tool the commands of privileged managers run through, sudo by default.
+    pub escalation: Option<Escalation>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
 pub struct PackageConfig {
-    oninstall: Option<HashMap<String, String>>,
+    pub package_order: Option<Vec<String>>,
+    /// Commands run after installing a package, keyed by package name. A post_install
+    /// hook in the package's manager file takes precedence.
+    pub oninstall: Option<BTreeMap<String, CommandLine>>,
+}
+
+/// Retention policy for `kaeru gen gc`. A generation is kept if any rule keeps it,
+/// tagged, pinned, current and latest generations are always kept.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct GcConfig {
+    pub keep_last: Option<usize>,
+    /// Duration such as "12h", "30d" or "2w".
+    #[serde(default, deserialize_with = "duration")]
+    pub keep_newer_than: Option<String>,
+}
+
+/// Reads a duration, rejecting ones `parse_duration` can't read so the error has a line number.
+fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    let duration = String::deserialize(deserializer)?;
+    if parse_duration(&duration).is_none() {
+        return Err(de::Error::custom(ERR_INVALID_DURATION));
+    }
+    Ok(Some(duration))
+}
+
+impl ManagerConfig {
+    pub fn call_order(&self) -> &[String] {
+        self.call_order.as_deref().unwrap_or_default()
+    }
+
+    pub fn setup_cmd(&self, manager: &str) -> Option<&String> {
+        self.setup_cmds.as_ref()?.get(manager)
+    }
+
+    pub fn setup_check(&self, manager: &str) -> String {
+        self.setup_checks
+            .as_ref()
+            .and_then(|checks| checks.get(manager))
+            .cloned()
+            .unwrap_or_else(|| format!("command -v {}", manager))
+    }
+
+    pub fn escalation(&self) -> Escalation {
+        self.escalation.unwrap_or_default()
+    }
+}
+
+impl PackageConfig {
+    pub fn package_order(&self) -> &[String] {
+        self.package_order.as_deref().unwrap_or_default()
+    }
+
+    pub fn oninstall(&self) -> BTreeMap<String, CommandLine> {
+        self.oninstall.clone().unwrap_or_default()
+    }
 }
 
 impl Config {
+    /// Reads a config file, configs using the old table names are read as if migrated.
     pub fn read(filename: String) -> Result<Self, Error> {
-        from_str(&get_contents_of(&filename).unwrap())
+        let with_filename = |err: &dyn std::fmt::Display| {
+            Error::new(
+                &format!("{}: {}", filename, err.to_string().trim_end()),
+                CONFIG_ERROR,
+            )
+        };
+        let contents = get_contents_of(&filename).map_err(|err| with_filename(&err))?;
+        Self::from_contents(&filename, &contents)
+    }
+
+    pub fn from_contents(filename: &str, contents: &str) -> Result<Self, Error> {
+        let contents = Self::migrate(contents).unwrap_or(contents.to_string());
+        let config: Self = from_str(&contents).map_err(|err| {
+            Error::new(
+                &format!("{}: {}", filename, err.to_string().trim_end()),
+                CONFIG_ERROR,
+            )
+        })?;
+        for (pkg, cmd) in config.package.oninstall() {
+            cmd.check_config_vars(&config.vars).map_err(|err| {
+                Error::new(
+                    &format!("{}: package.oninstall.{}: {}", filename, pkg, err.msg),
+                    CONFIG_ERROR,
+                )
+            })?;
+        }
+        Ok(config)
+    }
+
+    /// Renames the `[managers]` and `[packages]` tables of configs written by older
+    /// versions, comments are kept. Returns None if nothing needs renaming.
+    pub fn migrate(contents: &str) -> Option<String> {
+        let table: Table = from_str(contents).ok()?;
+        let renames: Vec<_> = RENAMED_TABLES
+            .iter()
+            .filter(|(old, new)| table.contains_key(*old) && !table.contains_key(*new))
+            .collect();
+        if renames.is_empty() {
+            return None;
+        }
+        let mut migrated = String::new();
+        for line in contents.lines() {
+            let header = line.trim_start().strip_prefix('[');
+            let renamed = renames.iter().find_map(|(old, new)| {
+                let rest = header?.strip_prefix(old)?;
+                (rest.starts_with(']') || rest.starts_with('.'))
+                    .then(|| format!("[{}{}", new, rest))
+            });
+            migrated.push_str(renamed.as_deref().unwrap_or(line));
+            migrated.push('\n');
+        }
+        Some(migrated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn old_config_with_oninstall_loads() {
+        let contents = r#"
+[managers]
+call_order = ["apt"]
+
+[packages]
+package_order = ["dev"]
+oninstall = { syncthing = "systemctl --user enable --now syncthing" }
+"#;
+        let config = Config::from_contents("config.toml", contents).unwrap();
+        assert_eq!(config.manager.call_order(), ["apt"]);
+        assert_eq!(
+            config.package.oninstall()["syncthing"],
+            CommandLine::Shell("systemctl --user enable --now syncthing".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_bad_durations_with_their_line() {
+        assert!(Config::from_contents("c", "[gc]\nkeep_newer_than = \"30d\"\n").is_ok());
+        let err = Config::from_contents("c", "[gc]\nkeep_newer_than = \"30 days\"\n")
+            .err()
+            .unwrap();
+        assert!(err.msg.contains("line 2"), "{}", err.msg);
+        assert!(err.msg.contains(ERR_INVALID_DURATION), "{}", err.msg);
+    }
+
+    #[test]
+    fn migrate_renames_old_tables() {
+        let old = "# kept\n[managers]\ncall_order = []\n\n[packages]\npackage_order = []\n";
+        assert_eq!(
+            Config::migrate(old).unwrap(),
+            "# kept\n[manager]\ncall_order = []\n\n[package]\npackage_order = []\n"
+        );
+    }
+
+    #[test]
+    fn migrate_renames_dotted_subtables() {
+        let old = "[managers]\n[managers.setup_cmds]\nflatpak = \"x\"\n[managersx]\n";
+        assert_eq!(
+            Config::migrate(old).unwrap(),
+            "[manager]\n[manager.setup_cmds]\nflatpak = \"x\"\n[managersx]\n"
+        );
+    }
+
+    #[test]
+    fn migrate_leaves_new_tables_alone() {
+        assert_eq!(Config::migrate("[manager]\n[package]\n"), None);
+        // Renaming would clash with the table that already exists.
+        assert_eq!(Config::migrate("[managers]\n[manager]\n"), None);
+        assert_eq!(
+            Config::migrate("[managers]\n[manager]\n[packages]\n").unwrap(),
+            "[managers]\n[manager]\n[package]\n"
+        );
     }
 }
@@ -1,17 +1,49 @@
-use std::collections::HashSet;
+use std::collections::BTreeSet;
+use std::fmt;
 
-use crate::{gen::Generation, manager::Manager};
+use serde::Serialize;
 
+use crate::{
+    error::Error,
+    gen::Generation,
+    util::{manager_name, serialize_manager_name},
+};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileStatus {
+    Added,
+    Deleted,
+    Modified,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CmdChange {
+    pub field: String,
+    pub old: String,
+    pub new: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
 pub struct GenDiff {
+    /// The manager file, serialized as the manager's name.
+    #[serde(serialize_with = "serialize_manager_name")]
     pub manager: String,
+    pub status: FileStatus,
     pub newly_installed: Vec<String>,
     pub removed: Vec<String>,
+    pub cmd_changes: Vec<CmdChange>,
 }
 
 impl GenDiff {
-    pub fn from_gens(gen1: &Generation, gen2: &Generation) -> Vec<Self> {
+    /// Computes what changed going from `gen2` to `gen1`, one entry per manager file.
+    /// Generations are passed with their IDs, which errors are reported with.
+    pub fn from_gens(
+        (genid1, gen1): (Option<usize>, &Generation),
+        (genid2, gen2): (Option<usize>, &Generation),
+    ) -> Result<Vec<Self>, Error> {
         let mut diffs = Vec::new();
-        let mut files = HashSet::new();
+        let mut files = BTreeSet::new();
         for file in gen1.snapshot.keys() {
             files.insert(file);
         }
@@ -22,38 +54,93 @@ impl GenDiff {
         for file in files {
             let gen1file = gen1.snapshot.get(file.as_str());
             let gen2file = gen2.snapshot.get(file.as_str());
-            if gen2file.is_some() && gen1file.is_some() {
-                let gen1manager: Manager = toml::from_str(&gen1file.unwrap().content).unwrap();
-                let gen2manager: Manager = toml::from_str(&gen2file.unwrap().content).unwrap();
-                let installed = gen1manager
-                    .items
-                    .difference(&gen2manager.items)
-                    .map(|ptr| ptr.clone());
-                let removed = gen2manager
-                    .items
-                    .difference(&gen1manager.items)
-                    .map(|ptr| ptr.clone());
-                diffs.push(GenDiff {
-                    newly_installed: installed.collect(),
-                    manager: file.clone(),
-                    removed: removed.collect(),
-                });
-            } else if gen2file.is_none() && gen1file.is_some() {
-                let manager: Manager = toml::from_str(&gen1file.unwrap().content).unwrap();
-                diffs.push(GenDiff {
-                    newly_installed: Vec::new(),
-                    removed: manager.items.into_iter().collect(),
-                    manager: file.clone(),
-                });
-            } else if gen2file.is_some() && gen1file.is_none() {
-                let manager: Manager = toml::from_str(&gen2file.unwrap().content).unwrap();
-                diffs.push(GenDiff {
-                    newly_installed: manager.items.into_iter().collect(),
-                    removed: Vec::new(),
-                    manager: file.clone(),
-                });
+            match (gen1file, gen2file) {
+                (Some(_), Some(_)) => {
+                    let gen1manager = gen1.manager(genid1, file)?;
+                    let gen2manager = gen2.manager(genid2, file)?;
+                    let cmd_changes = gen2manager
+                        .commands()
+                        .into_iter()
+                        .zip(gen1manager.commands())
+                        .filter(|((_, old), (_, new))| old != new)
+                        .map(|((field, old), (_, new))| CmdChange {
+                            field: field.to_string(),
+                            old: old.to_string(),
+                            new: new.to_string(),
+                        })
+                        .collect();
+                    diffs.push(GenDiff {
+                        newly_installed: gen1manager
+                            .packages()
+                            .difference(&gen2manager.packages())
+                            .cloned()
+                            .collect(),
+                        removed: gen2manager
+                            .packages()
+                            .difference(&gen1manager.packages())
+                            .cloned()
+                            .collect(),
+                        cmd_changes,
+                        status: FileStatus::Modified,
+                        manager: file.clone(),
+                    });
+                }
+                (None, Some(_)) => {
+                    let manager = gen2.manager(genid2, file)?;
+                    diffs.push(GenDiff {
+                        newly_installed: Vec::new(),
+                        removed: manager.packages().into_iter().collect(),
+                        cmd_changes: Vec::new(),
+                        status: FileStatus::Deleted,
+                        manager: file.clone(),
+                    });
+                }
+                (Some(_), None) => {
+                    let manager = gen1.manager(genid1, file)?;
+                    diffs.push(GenDiff {
+                        newly_installed: manager.packages().into_iter().collect(),
+                        removed: Vec::new(),
+                        cmd_changes: Vec::new(),
+                        status: FileStatus::Added,
+                        manager: file.clone(),
+                    });
+                }
+                (None, None) => {}
             }
         }
-        diffs
+        Ok(diffs)
+    }
+
+    /// True when the manager file exists in both generations and nothing in it changed.
+    pub fn is_empty(&self) -> bool {
+        self.status == FileStatus::Modified
+            && self.newly_installed.is_empty()
+            && self.removed.is_empty()
+            && self.cmd_changes.is_empty()
+    }
+}
+
+impl fmt::Display for GenDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = match self.status {
+            FileStatus::Added => "added",
+            FileStatus::Deleted => "deleted",
+            FileStatus::Modified => "modified",
+        };
+        writeln!(f, "{} ({})", manager_name(&self.manager), status)?;
+        for change in &self.cmd_changes {
+            writeln!(
+                f,
+                "  ~ {}: {:?} -> {:?}",
+                change.field, change.old, change.new
+            )?;
+        }
+        for pkg in &self.newly_installed {
+            writeln!(f, "  + {}", pkg)?;
+        }
+        for pkg in &self.removed {
+            writeln!(f, "  - {}", pkg)?;
+        }
+        Ok(())
     }
 }
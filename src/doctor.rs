@@ -0,0 +1,237 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::config::Config;
+use crate::gen::Generation;
+use crate::genman::genid_of;
+use crate::globals::{DEFAULT_CONFIG, ERR_GENFILE_NAME, GENERATION_FILE_EXT, MANAGER_FILE_EXT};
+use crate::manager::Manager;
+use crate::util::*;
+
+/// A problem found by `kaeru doctor`, with the repair `--fix` applies if it is safe.
+pub struct Problem {
+    pub msg: String,
+    pub fix: Option<Fix>,
+}
+
+pub enum Fix {
+    CreateDir(String),
+    WriteDefaultConfig,
+    /// Renames the tables of a config written by an older version.
+    MigrateConfig,
+    RemoveFile(String),
+    /// Renames a file kaeru can't read so it is ignored but kept around.
+    SetAside(String),
+    /// Points snapshot paths of a generation back into the manager dir.
+    RelocatePaths(usize),
+    /// Makes a generation whose parent was deleted by hand a root.
+    DropParent(usize),
+    FinishRestore,
+}
+
+impl Problem {
+    fn new(msg: String, fix: Option<Fix>) -> Self {
+        Self { msg, fix }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let tag = if self.fix.is_some() {
+            "fixable"
+        } else {
+            "manual"
+        };
+        write!(f, "[{}] {}", tag, self.msg)
+    }
+}
+
+impl Fix {
+    pub fn apply(&self) -> Result<(), crate::error::Error> {
+        match self {
+            Fix::CreateDir(dir) => mkdir_if_not_exists(dir)?,
+            Fix::WriteDefaultConfig => overwrite_contents_of(&conf_file(), DEFAULT_CONFIG)?,
+            Fix::MigrateConfig => {
+                let contents = get_contents_of(&conf_file())?;
+                if let Some(migrated) = Config::migrate(&contents) {
+                    overwrite_contents_of(&conf_file(), &migrated)?;
+                }
+            }
+            Fix::RemoveFile(file) => fs::remove_file(file)?,
+            Fix::SetAside(file) => fs::rename(file, format!("{}.invalid", file))?,
+            Fix::RelocatePaths(genid) => {
+                let mut gen = Generation::read(gen_file(*genid))?;
+                gen.snapshot = std::mem::take(&mut gen.snapshot)
+                    .into_values()
+                    .map(|mut conffile| {
+                        conffile.path = relocated(&conffile.path);
+                        (conffile.path.clone(), conffile)
+                    })
+                    .collect();
+                gen.store_objects()?;
+                overwrite_contents_of(&gen_file(*genid), &gen.as_json())?;
+            }
+            Fix::DropParent(genid) => {
+                let mut gen = Generation::read(gen_file(*genid))?;
+                gen.parent = None;
+                gen.store_objects()?;
+                overwrite_contents_of(&gen_file(*genid), &gen.as_json())?;
+            }
+            Fix::FinishRestore => Generation::recover_restore()?,
+        }
+        Ok(())
+    }
+}
+
+/// Checks every file kaeru reads and returns the problems found.
+pub fn diagnose() -> Vec<Problem> {
+    let mut problems = Vec::new();
+
+    for dir in [
+        conf_dir(),
+        managers_dir(),
+        gen_dir(),
+        objects_dir(),
+        templates_dir(),
+    ] {
+        if !fs::exists(&dir).unwrap_or(false) {
+            problems.push(Problem::new(
+                format!("{}: directory is missing", dir),
+                Some(Fix::CreateDir(dir)),
+            ));
+        }
+    }
+    for leftover in [staging_managers_dir(), old_managers_dir()] {
+        if fs::exists(&leftover).unwrap_or(false) {
+            problems.push(Problem::new(
+                format!("{}: left over from an interrupted rollback", leftover),
+                Some(Fix::FinishRestore),
+            ));
+        }
+    }
+
+    let mut config = None;
+    match get_contents_of(&conf_file()) {
+        Err(_) => problems.push(Problem::new(
+            format!("{}: config file is missing", conf_file()),
+            Some(Fix::WriteDefaultConfig),
+        )),
+        Ok(contents) => match Config::read(conf_file()) {
+            Err(err) => problems.push(Problem::new(err.msg, None)),
+            Ok(read) => {
+                if Config::migrate(&contents).is_some() {
+                    problems.push(Problem::new(
+                        format!(
+                            "{}: uses the old [managers] and [packages] tables",
+                            conf_file()
+                        ),
+                        Some(Fix::MigrateConfig),
+                    ));
+                }
+                config = Some(read);
+            }
+        },
+    }
+
+    if let Ok(files) = files_in_dir(&managers_dir(), "") {
+        for file in files {
+            if file.ends_with(MANAGER_FILE_EXT) {
+                // Config vars can only be checked against a config that loads.
+                let checked = Manager::new(file).and_then(|manager| match &config {
+                    Some(config) => manager.check_config_vars(&config.vars),
+                    None => Ok(()),
+                });
+                if let Err(err) = checked {
+                    problems.push(Problem::new(err.msg, None));
+                }
+            } else if is_temp_file(&file) {
+                problems.push(leftover_temp_file(file));
+            }
+        }
+    }
+
+    if let Ok(contents) = get_contents_of(&gen_state_file()) {
+        if let Err(err) = toml::from_str::<toml::Table>(&contents) {
+            problems.push(Problem::new(format!("{}: {}", gen_state_file(), err), None));
+        }
+    }
+
+    let files = files_in_dir(&gen_dir(), "").unwrap_or_default();
+    let genids: Vec<usize> = files.iter().filter_map(|file| genid_of(file)).collect();
+    for file in files {
+        if is_temp_file(&file) {
+            problems.push(leftover_temp_file(file));
+            continue;
+        }
+        let Some(genid) = genid_of(&file) else {
+            if file.ends_with(GENERATION_FILE_EXT) {
+                problems.push(Problem::new(
+                    format!("{}: {}", file, ERR_GENFILE_NAME),
+                    Some(Fix::SetAside(file)),
+                ));
+            }
+            continue;
+        };
+        let gen = match Generation::read(file.clone()) {
+            Ok(gen) => gen,
+            Err(err) => {
+                problems.push(Problem::new(err.msg, None));
+                continue;
+            }
+        };
+        let outside: Vec<&str> = gen
+            .snapshot
+            .iter()
+            .filter(|(key, conffile)| {
+                !Path::new(&conffile.path).starts_with(managers_dir()) || **key != conffile.path
+            })
+            .map(|(_, conffile)| conffile.path.as_str())
+            .collect();
+        if !outside.is_empty() {
+            problems.push(Problem::new(
+                format!(
+                    "{}: snapshot paths outside {}: {}",
+                    file,
+                    managers_dir(),
+                    outside.join(", ")
+                ),
+                Some(Fix::RelocatePaths(genid)),
+            ));
+        }
+        for (key, conffile) in gen.snapshot.iter() {
+            if let Err(err) = Manager::from_contents(key, &conffile.content) {
+                problems.push(Problem::new(format!("{}: {}", file, err.msg), None));
+            }
+        }
+        if let Some(parent) = gen.parent {
+            if !genids.contains(&parent) {
+                problems.push(Problem::new(
+                    format!("{}: parent generation {} does not exist", file, parent),
+                    Some(Fix::DropParent(genid)),
+                ));
+            }
+        }
+    }
+
+    problems
+}
+
+fn is_temp_file(file: &str) -> bool {
+    file.contains(".tmp-")
+}
+
+fn leftover_temp_file(file: String) -> Problem {
+    Problem::new(
+        format!(
+            "{}: temporary file left over from an interrupted write",
+            file
+        ),
+        Some(Fix::RemoveFile(file)),
+    )
+}
+
+/// The path a manager file would have in the current manager dir.
+fn relocated(path: &str) -> String {
+    format!("{}{}", managers_dir(), get_filename(path).unwrap_or(path))
+}
@@ -0,0 +1,64 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::{
+    error::Error,
+    manager::Manager,
+    util::{manager_name, serialize_manager_name},
+};
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Drift {
+    /// The manager file, serialized as the manager's name.
+    #[serde(serialize_with = "serialize_manager_name")]
+    pub manager: String,
+    /// Declared in `items` but not installed.
+    pub missing: Vec<String>,
+    /// Installed but not declared in `items`.
+    pub undeclared: Vec<String>,
+    pub in_sync: Vec<String>,
+}
+
+impl Drift {
+    pub fn from_manager(manager: &Manager) -> Result<Self, Error> {
+        let installed = manager.installed()?;
+        Ok(Self::from_sets(
+            &manager.file,
+            &manager.packages(),
+            &installed,
+        ))
+    }
+
+    pub fn from_sets(
+        manager: &str,
+        declared: &BTreeSet<String>,
+        installed: &BTreeSet<String>,
+    ) -> Self {
+        Self {
+            manager: manager.to_string(),
+            missing: declared.difference(installed).cloned().collect(),
+            undeclared: installed.difference(declared).cloned().collect(),
+            in_sync: declared.intersection(installed).cloned().collect(),
+        }
+    }
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", manager_name(&self.manager))?;
+        let sections = [
+            ("declared but missing", &self.missing),
+            ("installed but undeclared", &self.undeclared),
+            ("in sync", &self.in_sync),
+        ];
+        for (title, pkgs) in sections {
+            writeln!(f, "  {} ({}):", title, pkgs.len())?;
+            for pkg in pkgs {
+                writeln!(f, "    {}", pkg)?;
+            }
+        }
+        Ok(())
+    }
+}
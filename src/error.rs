@@ -5,6 +5,15 @@ pub struct Error {
 }
 
 pub const GEN_ERROR: i32 = 12;
+pub const MANAGER_ERROR: i32 = 13;
+pub const COMMAND_ERROR: i32 = 14;
+pub const PLAN_ERROR: i32 = 15;
+pub const CONFIG_ERROR: i32 = 16;
+pub const LOCK_ERROR: i32 = 17;
+pub const DOCTOR_ERROR: i32 = 18;
+pub const HOOK_ERROR: i32 = 19;
+pub const PRIVILEGE_ERROR: i32 = 20;
+pub const TIMEOUT_ERROR: i32 = 21;
 
 impl Error {
     pub fn new(msg: &str, code: i32) -> Self {
@@ -14,3 +23,13 @@ impl Error {
         }
     }
 }
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        let code = match err.kind() {
+            std::io::ErrorKind::TimedOut => TIMEOUT_ERROR,
+            _ => COMMAND_ERROR,
+        };
+        Self::new(&err.to_string(), code)
+    }
+}
@@ -1,41 +1,90 @@
 use crate::error::GEN_ERROR;
 use crate::globals::ERR_NO_CHANGES_TO_COMMIT;
+use crate::manager::Manager;
 use crate::util::*;
 use crate::{error::Error, globals::MANAGER_FILE_EXT};
 use serde::{Deserialize, Serialize};
 use serde_json;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::{fs, io};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Generation {
-    pub snapshot: HashMap<String, ConfFile>,
+    pub snapshot: BTreeMap<String, ConfFile>,
     pub epoch: i64,
     pub message: String,
     pub applied: bool,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub tags: BTreeSet<String>,
+    /// Pinned generations are never garbage collected.
+    #[serde(default)]
+    pub pinned: bool,
+    /// The generation this one was committed on top of.
+    #[serde(default)]
+    pub parent: Option<usize>,
 }
 
+/// A manager file in a snapshot. Contents live in the object store under their hash,
+/// generations written before that embed them and are read as is.
 #[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq)]
 pub struct ConfFile {
+    #[serde(default, skip_serializing)]
     pub content: String,
+    #[serde(default)]
+    pub hash: String,
     pub path: String,
     pub epoch: i64, // time modified/commited
 }
 
 impl Generation {
-    pub fn read(file: String) -> Result<Self, serde_json::Error> {
-        serde_json::from_str(&get_contents_of(&file).unwrap())
+    /// Reads a manager file of the snapshot, errors name the generation it is from.
+    pub fn manager(&self, genid: Option<usize>, file: &str) -> Result<Manager, Error> {
+        Manager::from_contents(file, &self.snapshot[file].content).map_err(|err| match genid {
+            Some(genid) => Error::new(&format!("Gen {}: {}", genid, err.msg), err.code),
+            None => err,
+        })
+    }
+
+    pub fn read(file: String) -> Result<Self, Error> {
+        let mut gen: Self = serde_json::from_str(&get_contents_of(&file)?)
+            .map_err(|err| Error::new(&format!("{}: {}", file, err), GEN_ERROR))?;
+        for conffile in gen.snapshot.values_mut() {
+            if conffile.hash.is_empty() {
+                conffile.hash = content_hash(&conffile.content);
+            } else {
+                conffile.content =
+                    get_contents_of(&object_file(&conffile.hash)).map_err(|err| {
+                        Error::new(
+                            &format!("{}: object {}: {}", file, conffile.hash, err),
+                            GEN_ERROR,
+                        )
+                    })?;
+            }
+        }
+        Ok(gen)
+    }
+
+    /// Writes the contents of every file in the snapshot to the object store.
+    pub fn store_objects(&self) -> io::Result<()> {
+        for conffile in self.snapshot.values() {
+            let object = object_file(&conffile.hash);
+            if !fs::exists(&object)? {
+                overwrite_contents_of(&object, &conffile.content)?;
+            }
+        }
+        Ok(())
     }
 
     pub fn default(message: Option<String>) -> Self {
         Self {
-            snapshot: HashMap::new(),
+            snapshot: BTreeMap::new(),
             epoch: epoch_time_secs(),
-            message: if message.is_none() {
-                "".into()
-            } else {
-                message.unwrap()
-            },
+            message: message.unwrap_or_default(),
             applied: false,
+            tags: BTreeSet::new(),
+            pinned: false,
+            parent: None,
         }
     }
 
@@ -43,13 +92,14 @@ impl Generation {
         let mut gen = Self::default(Some(message));
         for file in files_in_dir(&managers_dir(), MANAGER_FILE_EXT).unwrap() {
             let contents = get_contents_of(&file).unwrap();
-            if let Some(prev_content) = prev_gen.snapshot.get(&file) {
-                if contents == prev_content.content {
+            match prev_gen.snapshot.get(&file) {
+                Some(prev_content) if contents == prev_content.content => {
                     gen.snapshot.insert(file, prev_content.clone());
                 }
-            } else {
-                let conffile = ConfFile::from_contents(&file, contents);
-                gen.snapshot.insert(file, conffile);
+                _ => {
+                    let conffile = ConfFile::from_contents(&file, contents);
+                    gen.snapshot.insert(file, conffile);
+                }
             }
         }
 
@@ -74,24 +124,65 @@ impl Generation {
         serde_json::to_string_pretty(self).unwrap()
     }
 
+    /// Replaces the manager dir with the snapshot. The new set is staged in a sibling
+    /// dir and swapped in by renames that `recover_restore` finishes after a crash.
     pub fn restore(&self) {
-        remove_all_files_in_dir(&managers_dir()).unwrap();
+        if is_dry_run() {
+            return;
+        }
+        let staging = staging_managers_dir();
+        if fs::exists(&staging).unwrap() {
+            fs::remove_dir_all(&staging).unwrap();
+        }
+        mkdir_if_not_exists(&staging).unwrap();
         for (_, file) in self.snapshot.iter() {
-            overwrite_contents_of(&file.path, &file.content).unwrap();
+            let staged = format!("{}/{}", staging, get_filename(&file.path).unwrap());
+            overwrite_contents_of(&staged, &file.content).unwrap();
         }
+
+        let managers = managers_dir();
+        let managers = managers.trim_end_matches('/');
+        let old = old_managers_dir();
+        fs::rename(managers, &old).unwrap();
+        fs::rename(&staging, managers).unwrap();
+        sync_dir(Path::new(&conf_dir())).unwrap();
+        fs::remove_dir_all(&old).unwrap();
+    }
+
+    /// Completes or discards a restore that was interrupted.
+    pub fn recover_restore() -> io::Result<()> {
+        let managers = managers_dir();
+        let managers = managers.trim_end_matches('/');
+        let staging = staging_managers_dir();
+        let old = old_managers_dir();
+        if !fs::exists(managers)? && fs::exists(&staging)? {
+            fs::rename(&staging, managers)?;
+        }
+        if !fs::exists(managers)? && fs::exists(&old)? {
+            fs::rename(&old, managers)?;
+        }
+        for leftover in [staging, old] {
+            if fs::exists(&leftover)? {
+                fs::remove_dir_all(&leftover)?;
+            }
+        }
+        Ok(())
     }
 }
 
 impl ConfFile {
     pub fn new(file: &str, epoch: i64) -> Self {
+        let content = get_contents_of(file).unwrap();
         Self {
-            content: get_contents_of(file).unwrap(),
+            hash: content_hash(&content),
+            content,
             path: file.to_string(),
             epoch,
         }
     }
     pub fn from_contents(file: &str, content: String) -> Self {
         Self {
+            hash: content_hash(&content),
             content,
             path: file.to_string(),
             epoch: epoch_time_secs(),
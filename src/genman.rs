@@ -1,142 +1,580 @@
+use crate::config::{Config, GcConfig};
 use crate::diff::GenDiff;
 use crate::error::Error;
-use crate::error::GEN_ERROR;
+use crate::error::{CONFIG_ERROR, GEN_ERROR};
 use crate::gen::*;
-use crate::globals::{ERR_INVALID_GENID, GENERATION_FILE_EXT};
-use crate::manager::Manager;
+use crate::globals::{
+    ERR_GENFILE_NAME, ERR_GENS_EXIST, ERR_INVALID_DURATION, ERR_INVALID_GENID, ERR_NO_GENS,
+    ERR_NO_RETENTION, ERR_NUMERIC_TAG, ERR_REMOVE_APPLIED, ERR_REMOVE_HEAD, ERR_TAG_NOT_FOUND,
+    GENERATION_FILE_EXT,
+};
+use crate::manager::hook_failures;
+use crate::plan::Plan;
 use crate::util::*;
-use std::collections::{BTreeMap, HashSet};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 
 pub struct GenerationManager {
     gens: BTreeMap<usize, Generation>,
-    latest_gen: usize,
+    state: GenState,
+}
+
+/// Bookkeeping stored next to the generations. IDs are never reused, so removing a
+/// generation leaves a gap instead of renumbering the ones after it.
+#[derive(Serialize, Deserialize)]
+struct GenState {
+    next_id: usize,
+    /// The generation the manager files were last committed as or rolled back to, 0 if none.
+    #[serde(default)]
+    head: usize,
 }
 
 impl GenerationManager {
-    pub fn read() -> Self {
-        let genfiles = files_in_dir(&gen_dir(), GENERATION_FILE_EXT).unwrap();
+    pub fn read() -> Result<Self, Error> {
+        let genfiles = files_in_dir(&gen_dir(), GENERATION_FILE_EXT)?;
         let mut manager = Self {
             gens: BTreeMap::new(),
-            latest_gen: 0,
+            state: GenState {
+                next_id: 1,
+                head: 0,
+            },
         };
         for gen in genfiles {
-            let genname = get_filename(&gen).unwrap();
-            let genid: usize = genname
-                .strip_suffix(GENERATION_FILE_EXT)
-                .unwrap()
-                .parse::<usize>()
-                .unwrap();
-            manager.gens.insert(genid, Generation::read(gen).unwrap());
-            manager.latest_gen += 1;
+            let genid = genid_of(&gen)
+                .ok_or_else(|| Error::new(&format!("{}: {}", gen, ERR_GENFILE_NAME), GEN_ERROR))?;
+            manager.gens.insert(genid, Generation::read(gen)?);
         }
+        let latest_gen = manager.gens.keys().next_back().copied().unwrap_or(0);
 
-        manager
+        // Gen dirs written before IDs became stable have no state file, their
+        // contiguous IDs stay as they are and numbering continues after the last one.
+        if let Ok(contents) = get_contents_of(&gen_state_file()) {
+            manager.state = toml::from_str(&contents)
+                .map_err(|err| Error::new(&format!("{}: {}", gen_state_file(), err), GEN_ERROR))?;
+        }
+        manager.state.next_id = manager.state.next_id.max(latest_gen + 1);
+
+        // History written before generations had parents was linear.
+        if manager.state.head == 0 && latest_gen != 0 {
+            let mut parent = None;
+            for (genid, gen) in manager.gens.iter_mut() {
+                gen.parent = parent;
+                parent = Some(*genid);
+            }
+            manager.state.head = latest_gen;
+        }
+        Ok(manager)
     }
 
     pub fn commit(&mut self, message: String) -> Result<(), Error> {
         let next_gen: Generation;
-        if let Some(curr_gen) = self.gens.get(&self.latest_gen) {
-            next_gen = Generation::create(message, &curr_gen)?;
+        if let Some(curr_gen) = self.gens.get(&self.state.head) {
+            next_gen = Generation::create(message, curr_gen)?;
         } else {
             next_gen = Generation::genesis(message);
         }
-        self.latest_gen += 1;
-        self.gens.insert(self.latest_gen, next_gen);
-        self.renumber_gens();
+        self.push(next_gen);
         Ok(())
     }
 
-    pub fn apply_changes(&mut self, genid: Option<usize>) {
-        let curr_gen = self.gens.get(&self.latest_gen).unwrap();
-        if curr_gen.applied {
-            return ();
+    /// Stores a new generation under the next unused ID as a child of the head.
+    fn push(&mut self, mut gen: Generation) -> usize {
+        let genid = self.state.next_id;
+        self.state.next_id += 1;
+        gen.parent = Some(self.state.head).filter(|head| self.gens.contains_key(head));
+        self.gens.insert(genid, gen);
+        self.state.head = genid;
+        genid
+    }
+
+    /// Records the current manager files as the first, already applied generation.
+    pub fn genesis(&mut self, message: String) -> Result<(), Error> {
+        if !self.gens.is_empty() {
+            return Err(Error::new(ERR_GENS_EXIST, GEN_ERROR));
         }
-        let prev_gen: Generation = self
-            .gens
-            .get(
-                &(if genid.is_none() {
-                    self.latest_gen - 1
-                } else {
-                    genid.unwrap()
-                }),
-            )
-            .cloned()
-            .unwrap_or_else(|| Generation::default(None));
-        let diffs = GenDiff::from_gens(&curr_gen, &prev_gen);
-        for diff in diffs {
-            println!("{}", diff.manager);
-            let mut manager = Manager::new(diff.manager);
-            if !diff.newly_installed.is_empty() {
-                manager.install(diff.newly_installed).unwrap();
-            }
-            if !diff.removed.is_empty() {
-                manager.remove(diff.removed).unwrap();
-            }
+        let mut gen = Generation::genesis(message);
+        gen.applied = true;
+        self.push(gen);
+        Ok(())
+    }
+
+    /// Applies the head generation, diffing against `genid` or the current one.
+    pub fn apply_changes(&mut self, genid: Option<usize>, config: &Config) -> Result<(), Error> {
+        let curr_gen = self.get(self.state.head)?;
+        if curr_gen.applied && genid.is_none() {
+            return Ok(());
+        }
+        let plan = self.plan(genid, None, String::new(), config)?;
+        self.run_plan(plan)
+    }
+
+    /// Installs everything in the head generation, as if nothing was installed yet.
+    pub fn apply_from_scratch(&mut self, config: &Config) -> Result<(), Error> {
+        if self.gens.is_empty() {
+            return Err(Error::new(ERR_NO_GENS, GEN_ERROR));
+        }
+        let head = self.state.head;
+        let empty = Generation::default(None);
+        let plan = Plan::new(
+            (None, &empty),
+            (head, self.get(head)?),
+            String::new(),
+            config,
+        )?;
+        self.run_plan(plan)
+    }
+
+    fn run_plan(&mut self, plan: Plan) -> Result<(), Error> {
+        print!("{}", plan);
+        let failed_hooks = plan.execute()?;
+        self.mark_applied(plan.to);
+        hook_failures(failed_hooks)
+    }
+
+    /// Plans the transition to `to`, or the head generation, from `from`. Without `from`
+    /// the current generation is used, falling back to the parent of `to`.
+    pub fn plan(
+        &self,
+        from: Option<usize>,
+        to: Option<usize>,
+        fingerprint: String,
+        config: &Config,
+    ) -> Result<Plan, Error> {
+        if self.gens.is_empty() {
+            return Err(Error::new(ERR_NO_GENS, GEN_ERROR));
+        }
+        let to = to.unwrap_or(self.state.head);
+        let to_gen = self.get(to)?;
+        let from = from.or_else(|| self.current()).or(to_gen.parent);
+        let empty = Generation::default(None);
+        let from_gen = match from {
+            Some(from) => self.get(from)?,
+            None => &empty,
+        };
+        Plan::new((from, from_gen), (to, to_gen), fingerprint, config)
+    }
+
+    /// The generation that was applied last.
+    pub fn current(&self) -> Option<usize> {
+        self.gens
+            .iter()
+            .rev()
+            .find(|(_, gen)| gen.applied)
+            .map(|(genid, _)| *genid)
+    }
+
+    pub fn mark_applied(&mut self, genid: usize) {
+        for (id, gen) in self.gens.iter_mut() {
+            gen.applied = *id == genid;
+        }
+    }
+
+    /// Hash of every generation, used to detect changes between planning and applying.
+    pub fn fingerprint(&self) -> String {
+        let mut state = String::new();
+        for (genid, gen) in self.gens.iter() {
+            state.push_str(&genid.to_string());
+            state.push_str(&gen.as_json());
         }
+        content_hash(&state)
     }
 
     pub fn save(&mut self) {
-        self.renumber_gens();
-        remove_all_files_in_dir(&gen_dir()).unwrap();
+        if is_dry_run() {
+            return;
+        }
+        mkdir_if_not_exists(&objects_dir()).unwrap();
+        // Objects first and removals last, so an interrupted save never leaves a
+        // generation pointing at missing contents.
         for (genid, gen) in self.gens.iter() {
-            let savename = format!("{}{}.json", &gen_dir(), genid);
-            create_file_with_contents(&savename, &gen.as_json());
+            gen.store_objects().unwrap();
+            write_if_changed(&gen_file(*genid), &gen.as_json()).unwrap();
+        }
+        write_if_changed(&gen_state_file(), &toml::to_string(&self.state).unwrap()).unwrap();
+        for genfile in files_in_dir(&gen_dir(), GENERATION_FILE_EXT).unwrap() {
+            if !self.gens.keys().any(|genid| gen_file(*genid) == genfile) {
+                std::fs::remove_file(genfile).unwrap();
+            }
         }
+        self.prune_objects().unwrap();
     }
 
-    pub fn rollback(&mut self, genid: usize) -> Result<(), Error> {
-        if let Some(gen) = self.gens.get(&genid) {
-            gen.restore();
-            self.commit(gen.message.clone())?;
-            Ok(())
-        } else {
-            Err(Error::new(ERR_INVALID_GENID, GEN_ERROR))
+    /// Deletes objects no generation refers to anymore.
+    fn prune_objects(&self) -> std::io::Result<()> {
+        let referenced: HashSet<&str> = self
+            .gens
+            .values()
+            .flat_map(|gen| gen.snapshot.values())
+            .map(|conffile| conffile.hash.as_str())
+            .collect();
+        for object in files_in_dir(&objects_dir(), "")? {
+            if !referenced.contains(get_filename(&object).unwrap_or_default()) {
+                std::fs::remove_file(object)?;
+            }
         }
+        Ok(())
+    }
+
+    /// Restores the manager files of a generation and makes it the head, so the next
+    /// commit branches off it instead of the generations after it.
+    pub fn rollback(&mut self, genid: usize) -> Result<(), Error> {
+        let gen = self.get(genid)?;
+        gen.restore();
+        self.state.head = genid;
+        Ok(())
     }
 
     pub fn remove(&mut self, genid: usize) -> Result<(), Error> {
-        if let None = self.gens.remove(&genid) {
-            Err(Error::new(ERR_INVALID_GENID, GEN_ERROR))
-        } else {
-            Ok(())
+        if genid == self.state.head {
+            return Err(Error::new(ERR_REMOVE_HEAD, GEN_ERROR));
+        }
+        if Some(genid) == self.current() {
+            return Err(Error::new(ERR_REMOVE_APPLIED, GEN_ERROR));
         }
+        let gen = self
+            .gens
+            .remove(&genid)
+            .ok_or_else(|| Error::new(ERR_INVALID_GENID, GEN_ERROR))?;
+        self.reparent(genid, gen.parent);
+        Ok(())
     }
 
-    pub fn remove_duplicates(&mut self) {
-        let mut seem = HashSet::new();
+    /// Points the children of a removed generation at `parent`.
+    fn reparent(&mut self, removed: usize, parent: Option<usize>) {
+        for gen in self.gens.values_mut() {
+            if gen.parent == Some(removed) {
+                gen.parent = parent;
+            }
+        }
+    }
+
+    /// Resolves a generation ID or tag name to a generation ID.
+    pub fn resolve(&self, genref: &str) -> Result<usize, Error> {
+        if let Ok(genid) = genref.parse::<usize>() {
+            self.get(genid)?;
+            return Ok(genid);
+        }
+        self.tagged(genref)
+            .ok_or_else(|| Error::new(ERR_INVALID_GENID, GEN_ERROR))
+    }
+
+    fn tagged(&self, tag: &str) -> Option<usize> {
         self.gens
-            .retain(|_, gen| seem.insert(serde_json::to_string(&gen.snapshot).unwrap()));
-        self.renumber_gens();
+            .iter()
+            .find(|(_, gen)| gen.tags.contains(tag))
+            .map(|(genid, _)| *genid)
     }
 
-    pub fn list_gens(&self) {
-        let max_msg_len = self
+    /// Tags a generation, returns the generation the tag was moved from if it was in use.
+    pub fn tag(&mut self, tag: String, genid: usize) -> Result<Option<usize>, Error> {
+        if tag.parse::<usize>().is_ok() {
+            return Err(Error::new(ERR_NUMERIC_TAG, GEN_ERROR));
+        }
+        self.get(genid)?;
+        let prev = self.tagged(&tag);
+        if let Some(prev) = prev {
+            self.gens.get_mut(&prev).unwrap().tags.remove(&tag);
+        }
+        self.gens.get_mut(&genid).unwrap().tags.insert(tag);
+        Ok(prev.filter(|prev| *prev != genid))
+    }
+
+    pub fn untag(&mut self, tag: &str) -> Result<(), Error> {
+        let genid = self
+            .tagged(tag)
+            .ok_or_else(|| Error::new(ERR_TAG_NOT_FOUND, GEN_ERROR))?;
+        self.gens.get_mut(&genid).unwrap().tags.remove(tag);
+        Ok(())
+    }
+
+    pub fn head(&self) -> usize {
+        self.state.head
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.gens.is_empty()
+    }
+
+    pub fn get(&self, genid: usize) -> Result<&Generation, Error> {
+        self.gens
+            .get(&genid)
+            .ok_or_else(|| Error::new(ERR_INVALID_GENID, GEN_ERROR))
+    }
+
+    /// Changes going from generation `from` to generation `to`, skipping untouched managers.
+    pub fn diff(&self, from: usize, to: usize) -> Result<Vec<GenDiff>, Error> {
+        let diffs = GenDiff::from_gens((Some(to), self.get(to)?), (Some(from), self.get(from)?))?;
+        Ok(diffs.into_iter().filter(|diff| !diff.is_empty()).collect())
+    }
+
+    pub fn set_pinned(&mut self, genid: usize, pinned: bool) -> Result<(), Error> {
+        self.get(genid)?;
+        self.gens.get_mut(&genid).unwrap().pinned = pinned;
+        Ok(())
+    }
+
+    /// Removes the generations no retention rule keeps and returns their IDs.
+    pub fn gc(&mut self, policy: &GcConfig) -> Result<Vec<usize>, Error> {
+        if policy.keep_last.is_none() && policy.keep_newer_than.is_none() {
+            return Err(Error::new(ERR_NO_RETENTION, GEN_ERROR));
+        }
+        let min_epoch = match &policy.keep_newer_than {
+            Some(duration) => {
+                let secs = parse_duration(duration)
+                    .ok_or_else(|| Error::new(ERR_INVALID_DURATION, CONFIG_ERROR))?;
+                Some(epoch_time_secs() - secs)
+            }
+            None => None,
+        };
+        let keep_last: HashSet<usize> = self
+            .gens
+            .keys()
+            .rev()
+            .take(policy.keep_last.unwrap_or(0))
+            .copied()
+            .collect();
+        let current = self.current();
+
+        let removed: Vec<usize> = self
             .gens
+            .iter()
+            .filter(|(genid, gen)| {
+                let keep = keep_last.contains(genid)
+                    || min_epoch.is_some_and(|min_epoch| gen.epoch >= min_epoch)
+                    || !gen.tags.is_empty()
+                    || gen.pinned
+                    || Some(**genid) == current
+                    || **genid == self.state.head;
+                !keep
+            })
+            .map(|(genid, _)| *genid)
+            .collect();
+        for genid in removed.iter() {
+            let gen = self.gens.remove(genid).unwrap();
+            self.reparent(*genid, gen.parent);
+        }
+        Ok(removed)
+    }
+
+    pub fn remove_duplicates(&mut self) {
+        // Tags and children of removed duplicates move to the generation that is kept.
+        let mut seem: HashMap<String, usize> = HashMap::new();
+        let mut merged: Vec<(usize, usize, BTreeSet<String>)> = Vec::new();
+        self.gens.retain(|genid, gen| {
+            let snapshot = serde_json::to_string(&gen.snapshot).unwrap();
+            if let Some(kept) = seem.get(&snapshot) {
+                merged.push((*genid, *kept, std::mem::take(&mut gen.tags)));
+                false
+            } else {
+                seem.insert(snapshot, *genid);
+                true
+            }
+        });
+        for (removed, kept, tags) in merged {
+            self.gens.get_mut(&kept).unwrap().tags.extend(tags);
+            self.reparent(removed, Some(kept));
+            if self.state.head == removed {
+                self.state.head = kept;
+            }
+        }
+    }
+
+    /// One line describing a generation, shared by list and log.
+    fn describe(&self, genid: usize, gen: &Generation, width: usize) -> String {
+        let mut line = format!(
+            "{:2}: {:<width$} @ {}",
+            genid,
+            gen.message,
+            epoch_to_str(gen.epoch),
+            width = width
+        );
+        if !gen.tags.is_empty() {
+            line.push_str(&format!(
+                " [{}]",
+                gen.tags.iter().cloned().collect::<Vec<_>>().join(", ")
+            ));
+        }
+        let mut marks = Vec::new();
+        if genid == self.state.head {
+            marks.push("head");
+        }
+        if gen.applied {
+            marks.push("applied");
+        }
+        if gen.pinned {
+            marks.push("pinned");
+        }
+        if !marks.is_empty() {
+            line.push_str(&format!(" ({})", marks.join(", ")));
+        }
+        line
+    }
+
+    fn message_width(&self) -> usize {
+        self.gens
             .values()
             .map(|gen| gen.message.len())
             .max()
-            .unwrap_or(0);
+            .unwrap_or(0)
+            + 5
+    }
 
+    pub fn list_gens(&self) {
+        let width = self.message_width();
         for (id, gen) in self.gens.iter() {
-            println!(
-                "{:2}: {:<width$} @ {}",
-                id,
-                gen.message,
-                epoch_to_str(gen.epoch),
-                width = max_msg_len + 5
-            );
-        }
-    }
-
-    pub fn renumber_gens(&mut self) {
-        let mut sorted_gens: Vec<(usize, Generation)> =
-            std::mem::take(&mut self.gens).into_iter().collect();
-        sorted_gens.sort_by_key(|(_, gen)| gen.epoch);
-        let mut new_gens = BTreeMap::new();
-        for (new_id, (_, gen)) in sorted_gens.into_iter().enumerate() {
-            new_gens.insert(new_id + 1, gen);
-        }
-        self.gens = new_gens;
-        self.latest_gen = self.gens.len();
+            println!("{}", self.describe(*id, gen, width));
+        }
+    }
+
+    /// Prints the ancestry of the head, newest first.
+    pub fn log(&self) {
+        let width = self.message_width();
+        let mut genid = Some(self.state.head).filter(|head| self.gens.contains_key(head));
+        while let Some(id) = genid {
+            let gen = &self.gens[&id];
+            println!("* {}", self.describe(id, gen, width));
+            genid = gen.parent;
+        }
+    }
+
+    /// Prints every generation newest first, with one lane per branch.
+    pub fn log_graph(&self) {
+        for line in self.graph_lines() {
+            println!("{}", line);
+        }
+    }
+
+    fn graph_lines(&self) -> Vec<String> {
+        let width = self.message_width();
+        let mut lines = Vec::new();
+        // Each lane waits for the generation it leads to, children always have higher IDs.
+        let mut lanes: Vec<Option<usize>> = Vec::new();
+        for (genid, gen) in self.gens.iter().rev() {
+            let waiting: Vec<usize> = (0..lanes.len())
+                .filter(|lane| lanes[*lane] == Some(*genid))
+                .collect();
+            let col = match waiting.first() {
+                Some(col) => *col,
+                None => match lanes.iter().position(|lane| lane.is_none()) {
+                    Some(free) => free,
+                    None => {
+                        lanes.push(None);
+                        lanes.len() - 1
+                    }
+                },
+            };
+            // Branches that forked off this generation join its lane above it.
+            for merged in waiting.iter().skip(1).rev() {
+                lanes[*merged] = None;
+                let mut row: Vec<char> = lanes
+                    .iter()
+                    .flat_map(|lane| [if lane.is_some() { '|' } else { ' ' }, ' '])
+                    .collect();
+                row[merged * 2 - 1] = '/';
+                lines.push(row.iter().collect::<String>().trim_end().to_string());
+            }
+            while lanes.len() > col + 1 && lanes.last() == Some(&None) {
+                lanes.pop();
+            }
+            let row: String = (0..lanes.len())
+                .map(|lane| match lane {
+                    _ if lane == col => "* ",
+                    _ if lanes[lane].is_some() => "| ",
+                    _ => "  ",
+                })
+                .collect();
+            lines.push(format!("{}{}", row, self.describe(*genid, gen, width)));
+
+            lanes[col] = gen.parent;
+            while lanes.last() == Some(&None) {
+                lanes.pop();
+            }
+        }
+        lines
+    }
+}
+
+/// The ID a generation file is stored under, None if its name isn't a number.
+pub fn genid_of(genfile: &str) -> Option<usize> {
+    get_filename(genfile)?
+        .strip_suffix(GENERATION_FILE_EXT)?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_parents(parents: &[(usize, Option<usize>)]) -> GenerationManager {
+        let gens = parents
+            .iter()
+            .map(|(genid, parent)| {
+                let mut gen = Generation::default(Some("g".to_string()));
+                gen.epoch = 0;
+                gen.parent = *parent;
+                (*genid, gen)
+            })
+            .collect();
+        let head = parents.last().unwrap().0;
+        GenerationManager {
+            gens,
+            state: GenState {
+                next_id: head + 1,
+                head,
+            },
+        }
+    }
+
+    #[test]
+    fn graph_joins_a_fork_above_its_base() {
+        let genman = with_parents(&[(1, None), (2, Some(1)), (3, Some(1))]);
+        assert_eq!(
+            genman.graph_lines(),
+            [
+                "*  3: g      @ 1970-01-01 00:00:00 (head)",
+                "| *  2: g      @ 1970-01-01 00:00:00",
+                "|/",
+                "*  1: g      @ 1970-01-01 00:00:00",
+            ]
+        );
+    }
+
+    #[test]
+    fn graph_joins_several_forks_one_by_one() {
+        let genman = with_parents(&[(1, None), (2, Some(1)), (3, Some(1)), (4, Some(1))]);
+        assert_eq!(
+            genman.graph_lines(),
+            [
+                "*  4: g      @ 1970-01-01 00:00:00 (head)",
+                "| *  3: g      @ 1970-01-01 00:00:00",
+                "| | *  2: g      @ 1970-01-01 00:00:00",
+                "| |/",
+                "|/",
+                "*  1: g      @ 1970-01-01 00:00:00",
+            ]
+        );
+    }
+
+    #[test]
+    fn graph_keeps_a_linear_history_in_one_lane() {
+        let genman = with_parents(&[(1, None), (2, Some(1))]);
+        assert_eq!(
+            genman.graph_lines(),
+            [
+                "*  2: g      @ 1970-01-01 00:00:00 (head)",
+                "*  1: g      @ 1970-01-01 00:00:00",
+            ]
+        );
+    }
+
+    #[test]
+    fn remove_keeps_the_head_and_the_applied_generation() {
+        let mut genman = with_parents(&[(1, None), (2, Some(1)), (3, Some(2))]);
+        genman.mark_applied(1);
+        assert_eq!(genman.remove(3).unwrap_err().msg, ERR_REMOVE_HEAD);
+        assert_eq!(genman.remove(1).unwrap_err().msg, ERR_REMOVE_APPLIED);
+        genman.remove(2).unwrap();
+        assert_eq!(genman.gens[&3].parent, Some(1));
     }
 }
@@ -3,14 +3,90 @@ pub const ERR_INVALID_GENID: &str =
     "Specified generation ID is invalid, use kaeru gen list to list all generations";
 pub const SETUP_COMPLETE: &str = "Setup finished, run `kaeru help` to get started.";
 pub const ERR_NO_CHANGES_TO_COMMIT: &str = "No changes to commit, cannot create generation.";
+pub const ERR_MANAGER_NOT_FOUND: &str =
+    "Specified manager not found, manager files live in the manager directory";
+pub const ERR_NO_LISTCMD: &str = "Manager has no listcmd, cannot query installed packages";
+pub const ERR_GENS_EXIST: &str =
+    "Generations already exist, use kaeru gen commit to record the imported state";
+pub const IMPORT_GENESIS_MSG: &str = "Imported from system";
+pub const ERR_TEMPLATE_NOT_FOUND: &str =
+    "Specified template not found, use kaeru manager templates to list all templates";
+pub const ERR_MANAGER_EXISTS: &str = "A manager with this name already exists";
+pub const DRY_RUN_COMMANDS: &str = "Dry run, these commands would have been executed:";
+pub const DRY_RUN_NO_COMMANDS: &str = "Dry run, no commands would have been executed.";
+pub const DRY_RUN_NOT_QUERIED: &str = "dry run, installed packages were not queried";
+pub const ERR_PLAN_STALE: &str =
+    "Manager files or generations changed since the plan was made, run kaeru plan again";
+pub const ERR_NO_GENS: &str = "No generations yet, use kaeru gen commit to create one";
+pub const DEFAULT_PLAN_FILE: &str = "kaeru-plan.json";
+pub const ERR_NUMERIC_TAG: &str = "Tag names can't be numbers, they would shadow generation IDs";
+pub const ERR_TAG_NOT_FOUND: &str = "Specified tag not found, use kaeru gen list to list all tags";
+pub const ERR_NO_RETENTION: &str =
+    "No retention policy, set keep_last or keep_newer_than under [gc] in config.toml";
+pub const ERR_INVALID_DURATION: &str =
+    "Invalid duration, use a number followed by s, m, h, d or w, e.g. 30d";
+pub const ERR_REMOVE_HEAD: &str =
+    "Cannot remove the head generation, roll back to another generation first";
+pub const ERR_REMOVE_APPLIED: &str =
+    "Cannot remove the applied generation, the next apply plans its changes from it";
+pub const ERR_GENFILE_NAME: &str = "generation file names must be numeric IDs";
+pub const DOCTOR_HINT: &str = "run kaeru doctor to check kaeru's files";
+pub const DOCTOR_OK: &str = "No problems found.";
+pub const ERR_UNKNOWN_REQUIREMENT: &str = "no manager with that name exists";
+pub const ERR_REQUIRES_CYCLE: &str =
+    "Managers can't be ordered, requires forms a cycle between or before these managers";
+pub const ERR_GROUP_NOT_FOUND: &str =
+    "Specified group not found in any manager, use kaeru group list to list all groups";
+pub const ERR_GROUP_DISABLED: &str = "Group is disabled, enable it with kaeru group enable first";
+pub const GROUP_TOGGLED_HINT: &str = "commit and apply to install or remove its packages";
+pub const ERR_SETUP_INCOMPLETE: &str = "setup command ran but the manager is still missing";
+pub const ERR_EMPTY_COMMAND: &str = "Manager command is an empty argv array";
+pub const ERR_PACKAGE_NAME: &str =
+    "invalid package name, names can't be empty, start with - or contain control characters";
+/// Replaced by the packages in manager commands.
+pub const PACKAGES_PLACEHOLDER: &str = ":#?";
+/// Runs the shell string commands of managers that don't set `shell`.
+pub const DEFAULT_SHELL: &str = "/bin/sh";
+pub const ERR_CONFIG_VAR: &str = "config variable is not set, add it under [vars] in config.toml";
+pub const ERR_PACKAGES_ARG: &str =
+    "{{packages}} and :#? have to be a whole argument in argv commands, use {{packages_quoted}} inside one";
+pub const ERR_PER_PACKAGE: &str =
+    "{{package}} and {{version}} can't be used together with {{packages}} or {{packages_quoted}}";
+pub const ERR_PACKAGE_FIELD: &str =
+    "{{package}} and {{version}} only work in installcmd, removecmd and hooks";
+pub const ERR_MAX_BATCH: &str = "max_batch has to be at least 1";
+pub const ERR_ENV_NAME: &str = "variable names can't be empty or contain =";
+pub const ERR_CREDENTIALS: &str =
+    "Could not acquire credentials for privileged managers, set escalation under [manager] in config.toml";
+pub const ROOT_WARNING: &str =
+    "WARNING: kaeru is running as root, so it uses root's config dir instead of your user's";
 pub const MANAGER_FILE_EXT: &str = ".toml";
 pub const GENERATION_FILE_EXT: &str = ".json";
+pub const CONFIG_MIGRATED_MSG: &str =
+    "Renamed [managers] and [packages] in config.toml to [manager] and [package].";
 pub const DEFAULT_CONFIG: &str = r#"
-[managers]
+[manager]
 # call_order = ["manager1", "manager2", "manager3"]
+# Commands kaeru bootstrap runs to install a manager itself when it is missing.
+# setup_cmds = { flatpak = "flatpak remote-add --if-not-exists flathub https://dl.flathub.org/repo/flathub.flatpakrepo" }
+# Commands that succeed once a manager is usable, `command -v <manager>` if not set.
+# setup_checks = { flatpak = "flatpak remotes | grep -q flathub" }
+# Runs the commands of managers with `privileged = true`: sudo, doas, run0 or none.
+# escalation = "sudo"
 
-[packages]
+[package]
 # package_order = ["essentials", "dev", "games"]
 # These will be installed in the order provided.
 # The unspecified ones will be installed after these.
+# Commands run after a package is installed, like a post_install hook in its manager file.
+# oninstall = { syncthing = "systemctl --user enable --now syncthing" }
+
+[vars]
+# Values for {{config.NAME}} in manager commands.
+# mirror = "https://mirror.example.org"
+
+[gc]
+# Generations kept by kaeru gen gc, tagged, pinned and current ones are always kept.
+# keep_last = 10
+# keep_newer_than = "30d"
 "#;
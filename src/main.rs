@@ -2,13 +2,20 @@ use app::App;
 
 pub mod app;
 pub mod cli;
+pub mod command;
 pub mod config;
 pub mod diff;
+pub mod doctor;
+pub mod drift;
 pub mod error;
 pub mod gen;
 pub mod genman;
 pub mod globals;
 pub mod manager;
+pub mod order;
+pub mod plan;
+pub mod privilege;
+pub mod templates;
 pub mod util;
 
 fn main() {
@@ -1,50 +1,823 @@
-use crate::util::{get_contents_of, overwrite_contents_of, run_command};
+use crate::command::CommandLine;
+use crate::error::{Error, COMMAND_ERROR, HOOK_ERROR, MANAGER_ERROR, TIMEOUT_ERROR};
+use crate::globals::{
+    DEFAULT_SHELL, ERR_ENV_NAME, ERR_GROUP_DISABLED, ERR_INVALID_DURATION, ERR_MAX_BATCH,
+    ERR_NO_LISTCMD, ERR_PACKAGE_FIELD,
+};
+use crate::privilege::{acquire_credentials, escalate};
+use crate::templates::ManagerTemplate;
+use crate::util::{
+    command_output_with, get_contents_of, is_dry_run, parse_duration, run_command_with,
+    write_if_changed, RunOptions,
+};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
-use std::io;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::OnceLock;
 use toml::{from_str, to_string_pretty};
 
+static ONINSTALL: OnceLock<BTreeMap<String, CommandLine>> = OnceLock::new();
+
+/// Sets the `oninstall` commands of config.toml, they run as post_install hooks.
+pub fn set_oninstall(oninstall: BTreeMap<String, CommandLine>) {
+    let _ = ONINSTALL.set(oninstall);
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Manager {
-    installcmd: String,
-    removecmd: String,
-    synccmd: String,
-    upgradecmd: String,
-    pub items: HashSet<String>,
+    installcmd: CommandLine,
+    removecmd: CommandLine,
+    synccmd: CommandLine,
+    upgradecmd: CommandLine,
+    /// Prints the installed packages, one per line. Only the first word of each line is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    listcmd: Option<CommandLine>,
+    /// POSIX shell the commands written as strings run under, /bin/sh by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    shell: Option<String>,
+    /// Runs installcmd, removecmd, synccmd and upgradecmd through the escalation tool
+    /// set in config.toml. listcmd and hooks run as the user.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub privileged: bool,
+    /// Variables set for every command, passed through `env` so escalation keeps them.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    env: BTreeMap<String, String>,
+    /// Runs the commands with only the variables in `env` instead of kaeru's environment,
+    /// PATH has to be set there for programs outside /bin and /usr/bin.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    clear_env: bool,
+    /// Directory the commands run in, kaeru's working directory by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    workdir: Option<String>,
+    /// How long each type of command may run before it is killed.
+    #[serde(default, skip_serializing_if = "Timeouts::is_empty")]
+    timeout: Timeouts,
+    /// How installcmd and removecmd are invoked for several packages.
+    #[serde(default, skip_serializing_if = "Strategy::is_batch")]
+    strategy: Strategy,
+    /// Most packages passed to a single invocation, for managers that hit argv limits.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_batch: Option<usize>,
+    /// Managers that have to run before this one, e.g. the one installing nodejs for npm.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub requires: Vec<String>,
+    pub items: BTreeSet<String>,
+    /// Versions for `{{version}}` in commands, keyed by package name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub versions: BTreeMap<String, String>,
+    /// Named sets of packages, only the enabled ones are installed.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub groups: BTreeMap<String, Group>,
+    /// Commands run around installing or removing a package, keyed by package name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub hooks: BTreeMap<String, Hooks>,
 
     #[serde(skip)]
     pub file: String,
 }
 
+#[derive(Debug, Deserialize, Serialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    /// All packages in one invocation, a failure fails all of them.
+    #[default]
+    Batch,
+    /// One invocation per package.
+    PerPackage,
+    /// Batches, retried package by package when they fail.
+    BatchThenBisect,
+}
+
+impl Strategy {
+    fn is_batch(&self) -> bool {
+        *self == Strategy::Batch
+    }
+}
+
+/// One invocation of installcmd or removecmd and the per-package commands that retry it.
+pub struct PackageRun {
+    pub packages: Vec<String>,
+    pub argv: Vec<String>,
+    /// One command per package, empty unless the strategy is batch_then_bisect.
+    pub retry: Vec<Vec<String>>,
+    pub options: RunOptions,
+}
+
+/// Which packages a command worked for.
+#[derive(Default)]
+pub struct Outcome {
+    pub succeeded: Vec<String>,
+    pub failed: Vec<(String, Error)>,
+}
+
+impl Outcome {
+    /// Runs an invocation, falling back to its retry commands if it fails.
+    pub fn run(
+        &mut self,
+        packages: &[String],
+        argv: &[String],
+        retry: &[Vec<String>],
+        options: &RunOptions,
+    ) {
+        let Err(err) = run_command_with(argv, options) else {
+            self.succeeded.extend(packages.iter().cloned());
+            return;
+        };
+        // Retrying a batch that timed out package by package would wait out the timeout
+        // once per package, on e.g. a package database lock held by something else.
+        if retry.is_empty() || err.kind() == std::io::ErrorKind::TimedOut {
+            let err = Error::from(err);
+            for pkg in packages {
+                self.failed
+                    .push((pkg.clone(), Error::new(&err.msg, err.code)));
+            }
+            return;
+        }
+        for (pkg, argv) in packages.iter().zip(retry) {
+            match run_command_with(argv, options) {
+                Ok(()) => self.succeeded.push(pkg.clone()),
+                Err(err) => self.failed.push((pkg.clone(), err.into())),
+            }
+        }
+    }
+
+    /// Errors with every package that failed, `verb` being e.g. "install".
+    pub fn result(&self, verb: &str) -> Result<(), Error> {
+        if self.failed.is_empty() {
+            return Ok(());
+        }
+        let mut msg = format!(
+            "Failed to {} {} of {} package(s):",
+            verb,
+            self.failed.len(),
+            self.failed.len() + self.succeeded.len()
+        );
+        // Packages of a failed batch share its error, they are listed together.
+        let mut failed: Vec<(Vec<&str>, &str)> = Vec::new();
+        for (pkg, err) in self.failed.iter() {
+            match failed.last_mut() {
+                Some((pkgs, msg)) if *msg == err.msg => pkgs.push(pkg),
+                _ => failed.push((vec![pkg], &err.msg)),
+            }
+        }
+        for (pkgs, err) in failed {
+            msg.push_str(&format!("\n  {}: {}", pkgs.join(", "), err));
+        }
+        // A timeout in any of them is reported as one, the rest being collateral.
+        let code = if self.failed.iter().any(|(_, err)| err.code == TIMEOUT_ERROR) {
+            TIMEOUT_ERROR
+        } else {
+            COMMAND_ERROR
+        };
+        Err(Error::new(&msg, code))
+    }
+}
+
+/// Packages that are turned on and off together, across managers, with `kaeru group`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Group {
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub items: BTreeSet<String>,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Hooks {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pre_install: Option<CommandLine>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_install: Option<CommandLine>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pre_remove: Option<CommandLine>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_remove: Option<CommandLine>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hook {
+    PreInstall,
+    PostInstall,
+    PreRemove,
+    PostRemove,
+}
+
+impl Hook {
+    pub fn name(self) -> &'static str {
+        match self {
+            Hook::PreInstall => "pre_install",
+            Hook::PostInstall => "post_install",
+            Hook::PreRemove => "pre_remove",
+            Hook::PostRemove => "post_remove",
+        }
+    }
+
+    /// Pre hooks guard the command, a failure stops it from running.
+    pub fn is_pre(self) -> bool {
+        matches!(self, Hook::PreInstall | Hook::PreRemove)
+    }
+}
+
+impl Hooks {
+    fn get(&self, hook: Hook) -> Option<&CommandLine> {
+        match hook {
+            Hook::PreInstall => self.pre_install.as_ref(),
+            Hook::PostInstall => self.post_install.as_ref(),
+            Hook::PreRemove => self.pre_remove.as_ref(),
+            Hook::PostRemove => self.post_remove.as_ref(),
+        }
+    }
+}
+
+/// Durations such as "90s" or "10m", one per type of command. Commands with a timeout run
+/// in a process group of their own, so they can't prompt on the terminal.
+#[derive(Debug, Deserialize, Serialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Timeouts {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    install: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remove: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sync: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upgrade: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    list: Option<String>,
+    /// Applies to every hook.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hook: Option<String>,
+}
+
+impl Timeouts {
+    fn fields(&self) -> [(&'static str, &Option<String>); 6] {
+        [
+            ("install", &self.install),
+            ("remove", &self.remove),
+            ("sync", &self.sync),
+            ("upgrade", &self.upgrade),
+            ("list", &self.list),
+            ("hook", &self.hook),
+        ]
+    }
+
+    fn is_empty(&self) -> bool {
+        self.fields().iter().all(|(_, timeout)| timeout.is_none())
+    }
+}
+
+/// Describes a hook that failed, for `hook_failures`.
+pub fn hook_failure(hook: Hook, package: &str, err: &Error) -> String {
+    format!("{} hook of {} failed: {}", hook.name(), package, err.msg)
+}
+
+/// Errors with every failed hook, if there are any.
+pub fn hook_failures(failed: Vec<String>) -> Result<(), Error> {
+    if failed.is_empty() {
+        return Ok(());
+    }
+    Err(Error::new(&failed.join("\n"), HOOK_ERROR))
+}
+
 impl Manager {
-    pub fn new(filename: String) -> Self {
-        let mut manager: Self = from_str(&get_contents_of(&filename).unwrap()).unwrap();
-        manager.file = filename;
-        manager
+    pub fn new(filename: String) -> Result<Self, Error> {
+        let contents = get_contents_of(&filename)?;
+        Self::from_contents(&filename, &contents)
+    }
+
+    /// Builds a manager from file contents, e.g. those stored in a generation snapshot.
+    pub fn from_contents(filename: &str, contents: &str) -> Result<Self, Error> {
+        let mut manager: Self = from_str(contents).map_err(|err| {
+            Error::new(
+                &format!("{}: {}", filename, err.to_string().trim_end()),
+                MANAGER_ERROR,
+            )
+        })?;
+        manager.file = filename.to_string();
+        if manager.max_batch == Some(0) {
+            return Err(Error::new(
+                &format!("{}: {}", filename, ERR_MAX_BATCH),
+                MANAGER_ERROR,
+            ));
+        }
+        for (field, timeout) in manager.timeout.fields() {
+            if timeout
+                .as_deref()
+                .is_some_and(|timeout| parse_duration(timeout).is_none_or(|secs| secs <= 0))
+            {
+                return Err(Error::new(
+                    &format!("{}: timeout.{}: {}", filename, field, ERR_INVALID_DURATION),
+                    MANAGER_ERROR,
+                ));
+            }
+        }
+        if let Some(name) = manager
+            .env
+            .keys()
+            .find(|name| name.is_empty() || name.contains('='))
+        {
+            return Err(Error::new(
+                &format!("{}: env.{}: {}", filename, name, ERR_ENV_NAME),
+                MANAGER_ERROR,
+            ));
+        }
+        // Commands that never get packages can't run once per package.
+        for (field, cmd) in [
+            ("synccmd", Some(&manager.synccmd)),
+            ("upgradecmd", Some(&manager.upgradecmd)),
+            ("listcmd", manager.listcmd.as_ref()),
+        ] {
+            if cmd.is_some_and(CommandLine::is_per_package) {
+                return Err(Error::new(
+                    &format!("{}: {}: {}", filename, field, ERR_PACKAGE_FIELD),
+                    MANAGER_ERROR,
+                ));
+            }
+        }
+        Ok(manager)
+    }
+
+    pub fn from_template(template: ManagerTemplate, filename: String) -> Self {
+        Self {
+            installcmd: template.installcmd,
+            removecmd: template.removecmd,
+            synccmd: template.synccmd,
+            upgradecmd: template.upgradecmd,
+            listcmd: template.listcmd,
+            shell: None,
+            privileged: template.privileged,
+            env: BTreeMap::new(),
+            clear_env: false,
+            workdir: None,
+            timeout: Timeouts::default(),
+            strategy: Strategy::default(),
+            max_batch: None,
+            requires: Vec::new(),
+            items: BTreeSet::new(),
+            versions: BTreeMap::new(),
+            groups: BTreeMap::new(),
+            hooks: BTreeMap::new(),
+            file: filename,
+        }
+    }
+
+    /// Installs the packages, they are added to `items` or to the group only once the
+    /// command succeeded. A failed post_install hook is reported after the packages are added.
+    pub fn install(&mut self, packages: Vec<String>, group: Option<String>) -> Result<(), Error> {
+        if let Some(name) = &group {
+            if self.groups.get(name).is_some_and(|group| !group.enabled) {
+                return Err(Error::new(ERR_GROUP_DISABLED, MANAGER_ERROR));
+            }
+        }
+        let runs = self.install_cmd(&packages)?;
+        self.acquire_if_privileged()?;
+        self.run_hooks(Hook::PreInstall, &packages)?;
+        let mut outcome = Outcome::default();
+        for run in runs {
+            outcome.run(&run.packages, &run.argv, &run.retry, &run.options);
+        }
+        let packages = outcome.succeeded.clone();
+        match group {
+            Some(name) => self
+                .groups
+                .entry(name)
+                .or_insert_with(|| Group {
+                    enabled: true,
+                    items: BTreeSet::new(),
+                })
+                .items
+                .extend(packages.iter().cloned()),
+            None => self.items.extend(packages.iter().cloned()),
+        }
+        let hooks = self.run_hooks(Hook::PostInstall, &packages);
+        outcome.result("install").and(hooks)
+    }
+
+    /// The hook commands the packages define, as (package, command) pairs. `:#?` and
+    /// `{{package}}` in a hook stand for its package.
+    pub fn hook_cmds(
+        &self,
+        hook: Hook,
+        packages: &[String],
+    ) -> Result<Vec<(String, Vec<String>)>, Error> {
+        let mut cmds = Vec::new();
+        for pkg in packages {
+            let oninstall = match hook {
+                Hook::PostInstall => ONINSTALL.get().and_then(|oninstall| oninstall.get(pkg)),
+                _ => None,
+            };
+            let own = self.hooks.get(pkg).and_then(|hooks| hooks.get(hook));
+            if let Some(cmd) = own.or(oninstall) {
+                let pkg = std::slice::from_ref(pkg);
+                for invocation in cmd.invocations(self.shell(), pkg, &self.versions)? {
+                    cmds.push((pkg[0].clone(), self.with_env(invocation.argv)));
+                }
+            }
+        }
+        Ok(cmds)
+    }
+
+    /// Runs the hooks of the packages. A failed pre hook stops the rest, failed post hooks
+    /// are all run and reported together.
+    fn run_hooks(&self, hook: Hook, packages: &[String]) -> Result<(), Error> {
+        let mut failed = Vec::new();
+        for (pkg, cmd) in self.hook_cmds(hook, packages)? {
+            if let Err(err) = run_command_with(&cmd, &self.hook_options()) {
+                failed.push(hook_failure(hook, &pkg, &err.into()));
+                if hook.is_pre() {
+                    break;
+                }
+            }
+        }
+        hook_failures(failed)
+    }
+
+    /// Every package that should be installed, `items` and those of the enabled groups.
+    pub fn packages(&self) -> BTreeSet<String> {
+        let mut packages = self.items.clone();
+        for group in self.groups.values().filter(|group| group.enabled) {
+            packages.extend(group.items.iter().cloned());
+        }
+        packages
     }
 
-    pub fn install(&mut self, packages: Vec<String>) -> io::Result<()> {
-        let cmd = self.installcmd.replace(":#?", &packages.join(" "));
-        self.items.extend(packages);
-        run_command(&cmd)
+    /// Splits packages into install batches, groups in `package_order` first, then the
+    /// other groups by name, then the packages outside any group.
+    pub fn batches(
+        &self,
+        packages: &[String],
+        package_order: &[String],
+    ) -> Vec<(Option<String>, Vec<String>)> {
+        let mut names: Vec<&String> = self.groups.keys().collect();
+        names.sort_by_key(|name| {
+            package_order
+                .iter()
+                .position(|listed| listed == *name)
+                .unwrap_or(usize::MAX)
+        });
+        let mut remaining: Vec<String> = packages.to_vec();
+        let mut batches = Vec::new();
+        for name in names {
+            let (grouped, rest) = remaining
+                .into_iter()
+                .partition(|pkg| self.groups[name].items.contains(pkg));
+            remaining = rest;
+            if !grouped.is_empty() {
+                batches.push((Some(name.clone()), grouped));
+            }
+        }
+        if !remaining.is_empty() {
+            batches.push((None, remaining));
+        }
+        batches
+    }
+
+    /// Enables or disables a group, returns false if the manager doesn't have it.
+    pub fn set_group_enabled(&mut self, name: &str, enabled: bool) -> bool {
+        match self.groups.get_mut(name) {
+            Some(group) => {
+                group.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn shell(&self) -> &str {
+        self.shell.as_deref().unwrap_or(DEFAULT_SHELL)
+    }
+
+    pub fn install_cmd(&self, packages: &[String]) -> Result<Vec<PackageRun>, Error> {
+        self.package_runs(&self.installcmd, packages, &self.timeout.install)
+    }
+
+    pub fn remove_cmd(&self, packages: &[String]) -> Result<Vec<PackageRun>, Error> {
+        self.package_runs(&self.removecmd, packages, &self.timeout.remove)
+    }
+
+    /// Splits the packages into invocations according to `strategy` and `max_batch`.
+    fn package_runs(
+        &self,
+        cmd: &CommandLine,
+        packages: &[String],
+        timeout: &Option<String>,
+    ) -> Result<Vec<PackageRun>, Error> {
+        let size = match self.strategy {
+            Strategy::PerPackage => 1,
+            _ => self.max_batch.unwrap_or(packages.len()).max(1),
+        };
+        let mut runs = Vec::new();
+        for chunk in packages.chunks(size) {
+            for invocation in cmd.invocations(self.shell(), chunk, &self.versions)? {
+                let mut retry = Vec::new();
+                if self.strategy == Strategy::BatchThenBisect && invocation.packages.len() > 1 {
+                    for pkg in invocation.packages.iter() {
+                        let single = std::slice::from_ref(pkg);
+                        let mut invocations =
+                            cmd.invocations(self.shell(), single, &self.versions)?;
+                        retry.push(self.prepared(invocations.remove(0).argv));
+                    }
+                }
+                runs.push(PackageRun {
+                    packages: invocation.packages,
+                    argv: self.prepared(invocation.argv),
+                    retry,
+                    options: self.run_options(timeout),
+                });
+            }
+        }
+        Ok(runs)
+    }
+
+    /// The argv of a command that takes no packages, it is run exactly once.
+    fn single_argv(&self, cmd: &CommandLine) -> Result<Vec<String>, Error> {
+        let invocation = cmd
+            .invocations(self.shell(), &[], &self.versions)?
+            .remove(0);
+        Ok(invocation.argv)
+    }
+
+    /// Sets the manager's variables with `env`, if it has any or clears the environment.
+    fn with_env(&self, argv: Vec<String>) -> Vec<String> {
+        if self.env.is_empty() && !self.clear_env {
+            return argv;
+        }
+        let mut prefixed = vec!["env".to_string()];
+        if self.clear_env {
+            prefixed.push("-i".to_string());
+        }
+        prefixed.extend(
+            self.env
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value)),
+        );
+        prefixed.extend(argv);
+        prefixed
+    }
+
+    /// The argv of installcmd, removecmd, synccmd or upgradecmd as it is run.
+    fn prepared(&self, argv: Vec<String>) -> Vec<String> {
+        let argv = self.with_env(argv);
+        if self.privileged {
+            escalate(argv)
+        } else {
+            argv
+        }
+    }
+
+    fn run_options(&self, timeout: &Option<String>) -> RunOptions {
+        RunOptions {
+            workdir: self.workdir.clone(),
+            timeout: timeout
+                .as_deref()
+                .and_then(parse_duration)
+                .map(|secs| secs as u64),
+        }
+    }
+
+    pub fn sync_options(&self) -> RunOptions {
+        self.run_options(&self.timeout.sync)
+    }
+
+    pub fn hook_options(&self) -> RunOptions {
+        self.run_options(&self.timeout.hook)
+    }
+
+    /// Asks for credentials before the first privileged command, see `acquire_credentials`.
+    fn acquire_if_privileged(&self) -> Result<(), Error> {
+        if self.privileged {
+            acquire_credentials()?;
+        }
+        Ok(())
+    }
+
+    pub fn sync_cmd(&self) -> Result<Vec<String>, Error> {
+        Ok(self.prepared(self.single_argv(&self.synccmd)?))
+    }
+
+    pub fn sync(&self) -> Result<(), Error> {
+        self.acquire_if_privileged()?;
+        Ok(run_command_with(&self.sync_cmd()?, &self.sync_options())?)
+    }
+
+    pub fn upgrade(&self) -> Result<(), Error> {
+        self.acquire_if_privileged()?;
+        let argv = self.prepared(self.single_argv(&self.upgradecmd)?);
+        Ok(run_command_with(
+            &argv,
+            &self.run_options(&self.timeout.upgrade),
+        )?)
+    }
+
+    pub fn has_listcmd(&self) -> bool {
+        self.listcmd.is_some()
     }
 
-    pub fn sync(&self) -> io::Result<()> {
-        run_command(&self.synccmd)
+    /// Queries the packages that are actually installed using `listcmd`.
+    pub fn installed(&self) -> Result<BTreeSet<String>, Error> {
+        let listcmd = self
+            .listcmd
+            .as_ref()
+            .ok_or_else(|| Error::new(ERR_NO_LISTCMD, MANAGER_ERROR))?;
+        let argv = self.with_env(self.single_argv(listcmd)?);
+        let output = command_output_with(&argv, &self.run_options(&self.timeout.list))?;
+        Ok(output
+            .lines()
+            .filter_map(|line| line.split_whitespace().next())
+            .map(|pkg| pkg.to_string())
+            .collect())
     }
 
-    pub fn upgrade(&self) -> io::Result<()> {
-        run_command(&self.upgradecmd)
+    /// Removes the packages, they are taken out of `items` and every group only once the
+    /// command succeeded. A failed post_remove hook is reported after the packages are taken out.
+    pub fn remove(&mut self, packages: Vec<String>) -> Result<(), Error> {
+        let runs = self.remove_cmd(&packages)?;
+        self.acquire_if_privileged()?;
+        self.run_hooks(Hook::PreRemove, &packages)?;
+        let mut outcome = Outcome::default();
+        for run in runs {
+            outcome.run(&run.packages, &run.argv, &run.retry, &run.options);
+        }
+        let packages = outcome.succeeded.clone();
+        for pack in packages.iter() {
+            self.items.remove(pack);
+            for group in self.groups.values_mut() {
+                group.items.remove(pack);
+            }
+        }
+        let hooks = self.run_hooks(Hook::PostRemove, &packages);
+        outcome.result("remove").and(hooks)
     }
 
-    pub fn remove(&mut self, packages: Vec<String>) -> io::Result<()> {
-        let cmd = self.removecmd.replace(":#?", &packages.join(" "));
-        for pack in packages {
-            self.items.remove(&pack);
+    /// Errors if a command or hook uses a `{{config.NAME}}` that isn't set in `vars`.
+    pub fn check_config_vars(&self, vars: &BTreeMap<String, String>) -> Result<(), Error> {
+        let mut cmds = vec![
+            ("installcmd".to_string(), &self.installcmd),
+            ("removecmd".to_string(), &self.removecmd),
+            ("synccmd".to_string(), &self.synccmd),
+            ("upgradecmd".to_string(), &self.upgradecmd),
+        ];
+        if let Some(listcmd) = &self.listcmd {
+            cmds.push(("listcmd".to_string(), listcmd));
         }
-        run_command(&cmd)
+        for (pkg, hooks) in self.hooks.iter() {
+            for hook in [
+                Hook::PreInstall,
+                Hook::PostInstall,
+                Hook::PreRemove,
+                Hook::PostRemove,
+            ] {
+                if let Some(cmd) = hooks.get(hook) {
+                    cmds.push((format!("hooks.{}.{}", pkg, hook.name()), cmd));
+                }
+            }
+        }
+        for (field, cmd) in cmds {
+            cmd.check_config_vars(vars).map_err(|err| {
+                Error::new(
+                    &format!("{}: {}: {}", self.file, field, err.msg),
+                    MANAGER_ERROR,
+                )
+            })?;
+        }
+        Ok(())
     }
+
+    /// The manager's commands keyed by their field name in the manager file.
+    pub fn commands(&self) -> [(&'static str, String); 4] {
+        [
+            ("installcmd", self.installcmd.to_string()),
+            ("removecmd", self.removecmd.to_string()),
+            ("synccmd", self.synccmd.to_string()),
+            ("upgradecmd", self.upgradecmd.to_string()),
+        ]
+    }
+
+    /// The manager file as `save` writes it, independent of how the file is formatted on disk.
+    pub fn as_toml(&self) -> String {
+        to_string_pretty(self).unwrap()
+    }
+
     pub fn save(&self) {
-        overwrite_contents_of(&self.file, &to_string_pretty(self).unwrap()).unwrap();
+        if is_dry_run() {
+            return;
+        }
+        write_if_changed(&self.file, &self.as_toml()).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outcome(failed: &[(&str, i32)]) -> Outcome {
+        Outcome {
+            succeeded: vec!["ok".to_string()],
+            failed: failed
+                .iter()
+                .map(|(pkg, code)| (pkg.to_string(), Error::new("failed", *code)))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn result_is_ok_without_failures() {
+        assert!(outcome(&[]).result("install").is_ok());
+    }
+
+    #[test]
+    fn result_reports_command_failures() {
+        let err = outcome(&[("a", COMMAND_ERROR), ("b", COMMAND_ERROR)])
+            .result("install")
+            .unwrap_err();
+        assert_eq!(err.code, COMMAND_ERROR);
+        assert_eq!(
+            err.msg,
+            "Failed to install 2 of 3 package(s):\n  a, b: failed"
+        );
+    }
+
+    #[test]
+    fn result_reports_a_timeout_among_failures() {
+        let err = outcome(&[("a", COMMAND_ERROR), ("b", TIMEOUT_ERROR)])
+            .result("remove")
+            .unwrap_err();
+        assert_eq!(err.code, TIMEOUT_ERROR);
+    }
+
+    fn manager(settings: &str) -> Manager {
+        let contents = format!(
+            "installcmd = [\"inst\", \":#?\"]\nremovecmd = \"rm :#?\"\nsynccmd = \"true\"\n\
+             upgradecmd = \"true\"\nitems = []\n{}",
+            settings
+        );
+        Manager::from_contents("test.toml", &contents).unwrap()
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn batches_are_chunked_by_max_batch() {
+        let runs = manager("max_batch = 2")
+            .install_cmd(&names(&["a", "b", "c"]))
+            .unwrap();
+        let argvs: Vec<_> = runs.iter().map(|run| run.argv.clone()).collect();
+        assert_eq!(argvs, [names(&["inst", "a", "b"]), names(&["inst", "c"])]);
+        assert!(runs.iter().all(|run| run.retry.is_empty()));
+    }
+
+    #[test]
+    fn per_package_runs_one_invocation_each() {
+        let runs = manager("strategy = \"per_package\"\nmax_batch = 5")
+            .install_cmd(&names(&["a", "b"]))
+            .unwrap();
+        let packages: Vec<_> = runs.iter().map(|run| run.packages.clone()).collect();
+        assert_eq!(packages, [names(&["a"]), names(&["b"])]);
+    }
+
+    #[test]
+    fn batch_then_bisect_retries_each_package() {
+        let runs = manager("strategy = \"batch_then_bisect\"")
+            .install_cmd(&names(&["a", "b"]))
+            .unwrap();
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].argv, names(&["inst", "a", "b"]));
+        assert_eq!(
+            runs[0].retry,
+            [names(&["inst", "a"]), names(&["inst", "b"])]
+        );
+    }
+
+    #[test]
+    fn batch_then_bisect_skips_retries_of_a_single_package() {
+        let runs = manager("strategy = \"batch_then_bisect\"\nmax_batch = 1")
+            .install_cmd(&names(&["a", "b"]))
+            .unwrap();
+        assert!(runs.iter().all(|run| run.retry.is_empty()));
+    }
+
+    #[test]
+    fn timed_out_batches_are_not_retried() {
+        let mut outcome = Outcome::default();
+        let options = RunOptions {
+            workdir: None,
+            timeout: Some(1),
+        };
+        let start = std::time::Instant::now();
+        outcome.run(
+            &names(&["a", "b"]),
+            &names(&["sleep", "5"]),
+            &[names(&["sleep", "5"]), names(&["sleep", "5"])],
+            &options,
+        );
+        assert!(start.elapsed() < std::time::Duration::from_secs(3));
+        assert!(outcome.succeeded.is_empty());
+        assert_eq!(outcome.result("install").unwrap_err().code, TIMEOUT_ERROR);
     }
 }
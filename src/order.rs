@@ -0,0 +1,139 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::error::{Error, MANAGER_ERROR};
+use crate::globals::{ERR_REQUIRES_CYCLE, ERR_UNKNOWN_REQUIREMENT};
+
+/// Orders managers so every manager comes after the ones it requires. Among the managers
+/// that are free to run, those listed in `call_order` go first in that order, the rest
+/// follow by name. `requires` maps each manager name to the names it requires.
+pub fn call_order(
+    requires: &BTreeMap<String, Vec<String>>,
+    call_order: &[String],
+) -> Result<Vec<String>, Error> {
+    let rank = |name: &String| {
+        let position = call_order.iter().position(|listed| listed == name);
+        (position.unwrap_or(usize::MAX), name.clone())
+    };
+    let mut waiting_on: BTreeMap<&String, usize> = BTreeMap::new();
+    let mut dependents: BTreeMap<&String, Vec<&String>> = BTreeMap::new();
+    for (name, required) in requires {
+        for requirement in required {
+            if !requires.contains_key(requirement) {
+                return Err(Error::new(
+                    &format!(
+                        "{} requires {}: {}",
+                        name, requirement, ERR_UNKNOWN_REQUIREMENT
+                    ),
+                    MANAGER_ERROR,
+                ));
+            }
+            dependents.entry(requirement).or_default().push(name);
+        }
+        waiting_on.insert(name, required.iter().collect::<BTreeSet<_>>().len());
+    }
+
+    let mut ready: BTreeSet<_> = waiting_on
+        .iter()
+        .filter(|(_, count)| **count == 0)
+        .map(|(name, _)| rank(name))
+        .collect();
+    let mut order = Vec::new();
+    while let Some((_, name)) = ready.pop_first() {
+        let mut unblocked = BTreeSet::new();
+        for dependent in dependents.get(&name).into_iter().flatten() {
+            // A requirement listed twice still only blocks once.
+            if !unblocked.insert(*dependent) {
+                continue;
+            }
+            let count = waiting_on.get_mut(dependent).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                ready.insert(rank(dependent));
+            }
+        }
+        order.push(name);
+    }
+
+    if order.len() < requires.len() {
+        let cycle: Vec<_> = waiting_on
+            .into_iter()
+            .filter(|(_, count)| *count > 0)
+            .map(|(name, _)| name.as_str())
+            .collect();
+        return Err(Error::new(
+            &format!("{}: {}", ERR_REQUIRES_CYCLE, cycle.join(", ")),
+            MANAGER_ERROR,
+        ));
+    }
+    Ok(order)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn requires(edges: &[(&str, &[&str])]) -> BTreeMap<String, Vec<String>> {
+        edges
+            .iter()
+            .map(|(name, required)| {
+                let required = required.iter().map(|r| r.to_string()).collect();
+                (name.to_string(), required)
+            })
+            .collect()
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn requirements_come_first() {
+        let requires = requires(&[
+            ("cargo", &["rustup"]),
+            ("rustup", &["pacman"]),
+            ("pacman", &[]),
+        ]);
+        let order = call_order(&requires, &[]).unwrap();
+        assert_eq!(order, names(&["pacman", "rustup", "cargo"]));
+    }
+
+    #[test]
+    fn ties_follow_call_order_then_name() {
+        let requires = requires(&[("apt", &[]), ("cargo", &[]), ("flatpak", &[]), ("npm", &[])]);
+        let order = call_order(&requires, &names(&["npm", "cargo"])).unwrap();
+        assert_eq!(order, names(&["npm", "cargo", "apt", "flatpak"]));
+    }
+
+    #[test]
+    fn requirements_outrank_call_order() {
+        let requires = requires(&[("cargo", &["rustup"]), ("rustup", &[])]);
+        let order = call_order(&requires, &names(&["cargo", "rustup"])).unwrap();
+        assert_eq!(order, names(&["rustup", "cargo"]));
+    }
+
+    #[test]
+    fn requirement_listed_twice_blocks_once() {
+        let requires = requires(&[("cargo", &["rustup", "rustup"]), ("rustup", &[])]);
+        let order = call_order(&requires, &[]).unwrap();
+        assert_eq!(order, names(&["rustup", "cargo"]));
+    }
+
+    #[test]
+    fn rejects_unknown_requirements() {
+        let requires = requires(&[("cargo", &["rustup"])]);
+        let err = call_order(&requires, &[]).unwrap_err();
+        assert_eq!(err.code, MANAGER_ERROR);
+        assert_eq!(
+            err.msg,
+            format!("cargo requires rustup: {}", ERR_UNKNOWN_REQUIREMENT)
+        );
+    }
+
+    #[test]
+    fn rejects_cycles() {
+        let requires = requires(&[("a", &["b"]), ("b", &["c"]), ("c", &["a"]), ("d", &[])]);
+        let err = call_order(&requires, &[]).unwrap_err();
+        assert_eq!(err.code, MANAGER_ERROR);
+        assert_eq!(err.msg, format!("{}: a, b, c", ERR_REQUIRES_CYCLE));
+    }
+}
@@ -0,0 +1,349 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::command::display_argv;
+use crate::config::Config;
+use crate::diff::{FileStatus, GenDiff};
+use crate::error::{Error, HOOK_ERROR, PLAN_ERROR, TIMEOUT_ERROR};
+use crate::gen::Generation;
+use crate::globals::ERR_PLAN_STALE;
+use crate::manager::{hook_failure, Hook, Manager, Outcome};
+use crate::order;
+use crate::privilege::acquire_credentials;
+use crate::util::{
+    epoch_time_secs, get_contents_of, manager_name, overwrite_contents_of, run_command_with,
+    RunOptions,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Sync,
+    Install,
+    Remove,
+    PreInstall,
+    PostInstall,
+    PreRemove,
+    PostRemove,
+}
+
+impl Action {
+    fn hook(self) -> Option<Hook> {
+        match self {
+            Action::PreInstall => Some(Hook::PreInstall),
+            Action::PostInstall => Some(Hook::PostInstall),
+            Action::PreRemove => Some(Hook::PreRemove),
+            Action::PostRemove => Some(Hook::PostRemove),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Action::Sync => "sync",
+            Action::Install => "install",
+            Action::Remove => "remove",
+            _ => self.hook().unwrap().name(),
+        }
+    }
+}
+
+impl From<Hook> for Action {
+    fn from(hook: Hook) -> Self {
+        match hook {
+            Hook::PreInstall => Action::PreInstall,
+            Hook::PostInstall => Action::PostInstall,
+            Hook::PreRemove => Action::PreRemove,
+            Hook::PostRemove => Action::PostRemove,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PlanStep {
+    pub manager: String,
+    pub action: Action,
+    pub packages: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// Program and arguments, shell string commands are run by their shell with `-c`.
+    pub command: Vec<String>,
+    /// One command per package, run instead if `command` fails.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub retry: Vec<Vec<String>>,
+    /// Whether the command runs through the escalation tool.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub privileged: bool,
+    /// Working directory and timeout of the manager for this type of command.
+    #[serde(flatten)]
+    pub options: RunOptions,
+}
+
+/// The exact commands needed to go from generation `from` to generation `to`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Plan {
+    pub from: Option<usize>,
+    pub to: usize,
+    pub epoch: i64,
+    /// Hash of the manager files and generations the plan was made from.
+    pub fingerprint: String,
+    pub steps: Vec<PlanStep>,
+}
+
+impl Plan {
+    pub fn new(
+        from: (Option<usize>, &Generation),
+        to: (usize, &Generation),
+        fingerprint: String,
+        config: &Config,
+    ) -> Result<Self, Error> {
+        // Managers are ordered by everything in the target generation, since a changed
+        // manager may require one that didn't change.
+        let mut managers = BTreeMap::new();
+        for file in to.1.snapshot.keys() {
+            managers.insert(manager_name(file), to.1.manager(Some(to.0), file)?);
+        }
+        let mut diffs = GenDiff::from_gens((Some(to.0), to.1), from)?;
+        for diff in diffs.iter() {
+            // A deleted manager file only exists in the old generation.
+            if diff.status == FileStatus::Deleted {
+                managers.insert(
+                    manager_name(&diff.manager),
+                    from.1.manager(from.0, &diff.manager)?,
+                );
+            }
+        }
+        let requires = managers
+            .iter()
+            .map(|(name, manager)| (name.clone(), manager.requires.clone()))
+            .collect();
+        let order = order::call_order(&requires, config.manager.call_order())?;
+        diffs.sort_by_key(|diff| {
+            let name = manager_name(&diff.manager);
+            order.iter().position(|ordered| *ordered == name)
+        });
+
+        let mut steps = Vec::new();
+        for diff in diffs.iter_mut() {
+            let manager = &managers[&manager_name(&diff.manager)];
+            if !diff.newly_installed.is_empty() {
+                steps.push(PlanStep {
+                    manager: diff.manager.clone(),
+                    action: Action::Sync,
+                    packages: Vec::new(),
+                    group: None,
+                    command: manager.sync_cmd()?,
+                    retry: Vec::new(),
+                    privileged: manager.privileged,
+                    options: manager.sync_options(),
+                });
+                let packages = std::mem::take(&mut diff.newly_installed);
+                for (group, batch) in manager.batches(&packages, config.package.package_order()) {
+                    steps.extend(hook_steps(
+                        &diff.manager,
+                        manager,
+                        Hook::PreInstall,
+                        &batch,
+                    )?);
+                    let post = hook_steps(&diff.manager, manager, Hook::PostInstall, &batch)?;
+                    for invocation in manager.install_cmd(&batch)? {
+                        steps.push(PlanStep {
+                            manager: diff.manager.clone(),
+                            action: Action::Install,
+                            packages: invocation.packages,
+                            group: group.clone(),
+                            command: invocation.argv,
+                            retry: invocation.retry,
+                            privileged: manager.privileged,
+                            options: invocation.options,
+                        });
+                    }
+                    steps.extend(post);
+                }
+            }
+        }
+        // Removals run in reverse, so nothing is removed before the managers requiring it.
+        for diff in diffs.into_iter().rev() {
+            let manager = &managers[&manager_name(&diff.manager)];
+            if !diff.removed.is_empty() {
+                steps.extend(hook_steps(
+                    &diff.manager,
+                    manager,
+                    Hook::PreRemove,
+                    &diff.removed,
+                )?);
+                let post = hook_steps(&diff.manager, manager, Hook::PostRemove, &diff.removed)?;
+                for invocation in manager.remove_cmd(&diff.removed)? {
+                    steps.push(PlanStep {
+                        manager: diff.manager.clone(),
+                        action: Action::Remove,
+                        packages: invocation.packages,
+                        group: None,
+                        command: invocation.argv,
+                        retry: invocation.retry,
+                        privileged: manager.privileged,
+                        options: invocation.options,
+                    });
+                }
+                steps.extend(post);
+            }
+        }
+
+        Ok(Self {
+            from: from.0,
+            to: to.0,
+            epoch: epoch_time_secs(),
+            fingerprint,
+            steps,
+        })
+    }
+
+    pub fn read(file: &str) -> Result<Self, Error> {
+        let contents = get_contents_of(file)?;
+        serde_json::from_str(&contents)
+            .map_err(|err| Error::new(&format!("{}: {}", file, err), PLAN_ERROR))
+    }
+
+    pub fn save(&self, file: &str) -> Result<(), Error> {
+        overwrite_contents_of(file, &serde_json::to_string_pretty(self).unwrap())?;
+        Ok(())
+    }
+
+    /// Errors if the manager files or generations changed since the plan was made.
+    pub fn ensure_fresh(&self, fingerprint: &str) -> Result<(), Error> {
+        if self.fingerprint != fingerprint {
+            return Err(Error::new(ERR_PLAN_STALE, PLAN_ERROR));
+        }
+        Ok(())
+    }
+
+    /// Runs every step in order. Packages that fail to install or remove are reported
+    /// together at the end, like `Manager::install` does, and their post hooks are skipped.
+    /// A failed sync or pre hook stops the plan. Failed post hooks don't, since their
+    /// packages are already in place, they are returned instead.
+    /// Credentials for privileged steps are acquired once before the first step.
+    pub fn execute(&self) -> Result<Vec<String>, Error> {
+        if self.steps.iter().any(|step| step.privileged) {
+            acquire_credentials()?;
+        }
+        let mut installs = Outcome::default();
+        let mut removals = Outcome::default();
+        let mut failed_packages = BTreeSet::new();
+        let mut failed_hooks = Vec::new();
+        let mut stopped = None;
+        for step in &self.steps {
+            let outcome = match step.action {
+                Action::Install => &mut installs,
+                Action::Remove => &mut removals,
+                _ => {
+                    let skipped = step.action.hook().is_some_and(|hook| !hook.is_pre())
+                        && step.packages.iter().any(|pkg| {
+                            failed_packages.contains(&(step.manager.clone(), pkg.clone()))
+                        });
+                    if skipped {
+                        continue;
+                    }
+                    let Err(err) =
+                        run_command_with(&step.command, &step.options).map_err(Error::from)
+                    else {
+                        continue;
+                    };
+                    match step.action.hook() {
+                        Some(hook) if !hook.is_pre() => {
+                            failed_hooks.push(hook_failure(hook, &step.packages.join(" "), &err))
+                        }
+                        Some(hook) => {
+                            let failure = hook_failure(hook, &step.packages.join(" "), &err);
+                            stopped = Some(Error::new(&failure, HOOK_ERROR));
+                            break;
+                        }
+                        None => {
+                            stopped = Some(err);
+                            break;
+                        }
+                    }
+                    continue;
+                }
+            };
+            let before = outcome.failed.len();
+            outcome.run(&step.packages, &step.command, &step.retry, &step.options);
+            for (pkg, _) in outcome.failed[before..].iter() {
+                failed_packages.insert((step.manager.clone(), pkg.clone()));
+            }
+        }
+
+        let errors: Vec<Error> = [
+            stopped.map_or(Ok(()), Err),
+            installs.result("install"),
+            removals.result("remove"),
+        ]
+        .into_iter()
+        .filter_map(Result::err)
+        .collect();
+        let Some(first) = errors.first() else {
+            return Ok(failed_hooks);
+        };
+        let mut msgs: Vec<&str> = errors.iter().map(|err| err.msg.as_str()).collect();
+        msgs.extend(failed_hooks.iter().map(String::as_str));
+        let code = errors
+            .iter()
+            .find(|err| err.code == TIMEOUT_ERROR)
+            .unwrap_or(first)
+            .code;
+        Err(Error::new(&msgs.join("\n"), code))
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.from {
+            Some(from) => writeln!(f, "Plan from Gen {} to Gen {}", from, self.to)?,
+            None => writeln!(f, "Plan to Gen {}", self.to)?,
+        }
+        if self.steps.is_empty() {
+            return writeln!(f, "  nothing to do");
+        }
+        for (i, step) in self.steps.iter().enumerate() {
+            let action = step.action.name();
+            let group = match &step.group {
+                Some(group) => format!(" ({})", group),
+                None => String::new(),
+            };
+            writeln!(
+                f,
+                "{:3}. [{}] {}{}: {}",
+                i + 1,
+                manager_name(&step.manager),
+                action,
+                group,
+                display_argv(&step.command)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// One step per package that defines the hook.
+fn hook_steps(
+    file: &str,
+    manager: &Manager,
+    hook: Hook,
+    packages: &[String],
+) -> Result<Vec<PlanStep>, Error> {
+    Ok(manager
+        .hook_cmds(hook, packages)?
+        .into_iter()
+        .map(|(pkg, command)| PlanStep {
+            manager: file.to_string(),
+            action: hook.into(),
+            packages: vec![pkg],
+            group: None,
+            command,
+            retry: Vec::new(),
+            privileged: false,
+            options: manager.hook_options(),
+        })
+        .collect())
+}
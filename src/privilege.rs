@@ -0,0 +1,101 @@
+//! Running the commands of privileged managers through sudo, doas or run0.
+
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, PRIVILEGE_ERROR};
+use crate::globals::ERR_CREDENTIALS;
+use crate::util::{is_dry_run, run_command};
+
+/// How often sudo's cached credentials are refreshed while kaeru runs.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+static ESCALATION: OnceLock<Escalation> = OnceLock::new();
+static ACQUIRED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Escalation {
+    #[default]
+    Sudo,
+    Doas,
+    Run0,
+    /// Commands run as they are, for machines where kaeru runs as root.
+    None,
+}
+
+impl Escalation {
+    fn program(self) -> Option<&'static str> {
+        match self {
+            Escalation::Sudo => Some("sudo"),
+            Escalation::Doas => Some("doas"),
+            Escalation::Run0 => Some("run0"),
+            Escalation::None => None,
+        }
+    }
+
+    /// A command that asks for credentials up front. doas and run0 only skip later prompts
+    /// if they are set up to remember them, `persist` in doas.conf or a polkit rule.
+    fn acquire_argv(self) -> Option<Vec<String>> {
+        let args: &[&str] = match self {
+            Escalation::Sudo => &["sudo", "-v"],
+            Escalation::Doas => &["doas", "true"],
+            Escalation::Run0 => &["run0", "true"],
+            Escalation::None => return None,
+        };
+        Some(args.iter().map(|arg| arg.to_string()).collect())
+    }
+}
+
+/// Sets the escalation tool, read from config.toml at startup.
+pub fn set_escalation(escalation: Escalation) {
+    let _ = ESCALATION.set(escalation);
+}
+
+fn escalation() -> Escalation {
+    ESCALATION.get().copied().unwrap_or_default()
+}
+
+pub fn is_root() -> bool {
+    // SAFETY: geteuid has no preconditions and can't fail.
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Runs the argv through the escalation tool, unless kaeru already runs as root.
+pub fn escalate(argv: Vec<String>) -> Vec<String> {
+    match escalation().program() {
+        Some(program) if !is_root() => std::iter::once(program.to_string()).chain(argv).collect(),
+        _ => argv,
+    }
+}
+
+/// Asks for credentials once before privileged commands run. sudo's are then refreshed
+/// in the background until kaeru exits, so long runs don't prompt again halfway.
+pub fn acquire_credentials() -> Result<(), Error> {
+    if is_root() || ACQUIRED.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    let Some(argv) = escalation().acquire_argv() else {
+        return Ok(());
+    };
+    run_command(&argv)
+        .map_err(|err| Error::new(&format!("{}: {}", ERR_CREDENTIALS, err), PRIVILEGE_ERROR))?;
+    ACQUIRED.store(true, Ordering::Relaxed);
+    if escalation() == Escalation::Sudo && !is_dry_run() {
+        thread::spawn(|| loop {
+            thread::sleep(REFRESH_INTERVAL);
+            let _ = Command::new("sudo")
+                .args(["-n", "-v"])
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status();
+        });
+    }
+    Ok(())
+}
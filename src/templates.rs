@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+use std::fs;
+
+use serde::{Deserialize, Serialize};
+
+use crate::command::CommandLine;
+use crate::error::{Error, MANAGER_ERROR};
+use crate::globals::{ERR_TEMPLATE_NOT_FOUND, MANAGER_FILE_EXT};
+use crate::util::{files_in_dir, get_contents_of, manager_name, templates_dir};
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ManagerTemplate {
+    pub installcmd: CommandLine,
+    pub removecmd: CommandLine,
+    pub synccmd: CommandLine,
+    pub upgradecmd: CommandLine,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub listcmd: Option<CommandLine>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub privileged: bool,
+
+    /// False for templates read from the templates directory.
+    #[serde(skip)]
+    pub builtin: bool,
+}
+
+/// Builtin commands are argv arrays, only listcmd needs a shell for its pipes.
+struct Builtin {
+    name: &'static str,
+    privileged: bool,
+    installcmd: &'static [&'static str],
+    removecmd: &'static [&'static str],
+    synccmd: &'static [&'static str],
+    upgradecmd: &'static [&'static str],
+    listcmd: &'static str,
+}
+
+fn argv(args: &[&str]) -> CommandLine {
+    CommandLine::Argv(args.iter().map(|arg| arg.to_string()).collect())
+}
+
+const BUILTINS: &[Builtin] = &[
+    Builtin {
+        name: "apt",
+        privileged: true,
+        installcmd: &["apt-get", "install", "-y", ":#?"],
+        removecmd: &["apt-get", "remove", "-y", ":#?"],
+        synccmd: &["apt-get", "update"],
+        upgradecmd: &["apt-get", "upgrade", "-y"],
+        listcmd: "apt-mark showmanual",
+    },
+    Builtin {
+        name: "pacman",
+        privileged: true,
+        installcmd: &["pacman", "-S", "--needed", "--noconfirm", ":#?"],
+        removecmd: &["pacman", "-Rns", "--noconfirm", ":#?"],
+        // Refreshing the databases without upgrading is a partial upgrade, Arch doesn't
+        // support installing on top of one.
+        synccmd: &["pacman", "-Syu", "--noconfirm"],
+        upgradecmd: &["pacman", "-Syu", "--noconfirm"],
+        listcmd: "pacman -Qqe",
+    },
+    Builtin {
+        name: "dnf",
+        privileged: true,
+        installcmd: &["dnf", "install", "-y", ":#?"],
+        removecmd: &["dnf", "remove", "-y", ":#?"],
+        synccmd: &["dnf", "makecache"],
+        upgradecmd: &["dnf", "upgrade", "-y"],
+        listcmd: "dnf repoquery --userinstalled --qf '%{name}\\n'",
+    },
+    Builtin {
+        name: "zypper",
+        privileged: true,
+        installcmd: &["zypper", "--non-interactive", "install", ":#?"],
+        removecmd: &["zypper", "--non-interactive", "remove", ":#?"],
+        synccmd: &["zypper", "--non-interactive", "refresh"],
+        upgradecmd: &["zypper", "--non-interactive", "update"],
+        // Only packages installed on request, the name is the third column of the table.
+        listcmd: "zypper --quiet packages --userinstalled | awk -F'|' '$1 ~ /i/ { gsub(/ /, \"\", $3); print $3 }' | sort -u",
+    },
+    Builtin {
+        name: "flatpak",
+        privileged: false,
+        installcmd: &["flatpak", "install", "-y", "--noninteractive", ":#?"],
+        removecmd: &["flatpak", "uninstall", "-y", "--noninteractive", ":#?"],
+        synccmd: &["flatpak", "update", "-y", "--appstream"],
+        upgradecmd: &["flatpak", "update", "-y", "--noninteractive"],
+        listcmd: "flatpak list --app --columns=application",
+    },
+    Builtin {
+        name: "snap",
+        privileged: true,
+        installcmd: &["snap", "install", ":#?"],
+        removecmd: &["snap", "remove", ":#?"],
+        synccmd: &["snap", "refresh", "--list"],
+        upgradecmd: &["snap", "refresh"],
+        listcmd: "snap list | tail -n +2",
+    },
+    Builtin {
+        name: "brew",
+        privileged: false,
+        installcmd: &["brew", "install", ":#?"],
+        removecmd: &["brew", "uninstall", ":#?"],
+        synccmd: &["brew", "update"],
+        upgradecmd: &["brew", "upgrade"],
+        listcmd: "brew leaves --installed-on-request",
+    },
+    Builtin {
+        name: "cargo",
+        privileged: false,
+        installcmd: &["cargo", "install", ":#?"],
+        removecmd: &["cargo", "uninstall", ":#?"],
+        synccmd: &["true"],
+        upgradecmd: &["cargo", "install-update", "-a"],
+        listcmd: "cargo install --list | grep -v '^ '",
+    },
+    Builtin {
+        name: "pipx",
+        privileged: false,
+        installcmd: &["pipx", "install", ":#?"],
+        removecmd: &["pipx", "uninstall", ":#?"],
+        synccmd: &["true"],
+        upgradecmd: &["pipx", "upgrade-all"],
+        listcmd: "pipx list --short",
+    },
+    Builtin {
+        name: "npm",
+        privileged: false,
+        installcmd: &["npm", "install", "-g", ":#?"],
+        removecmd: &["npm", "uninstall", "-g", ":#?"],
+        synccmd: &["true"],
+        upgradecmd: &["npm", "update", "-g"],
+        listcmd: "npm ls -g --depth=0 --parseable | tail -n +2 | sed 's|.*/node_modules/||'",
+    },
+];
+
+impl ManagerTemplate {
+    /// Every known template by name, user templates take precedence over builtin ones.
+    pub fn catalogue() -> Result<BTreeMap<String, Self>, Error> {
+        let mut templates: BTreeMap<String, Self> = BUILTINS
+            .iter()
+            .map(|builtin| {
+                let template = Self {
+                    installcmd: argv(builtin.installcmd),
+                    removecmd: argv(builtin.removecmd),
+                    synccmd: argv(builtin.synccmd),
+                    upgradecmd: argv(builtin.upgradecmd),
+                    listcmd: Some(CommandLine::Shell(builtin.listcmd.to_string())),
+                    privileged: builtin.privileged,
+                    builtin: true,
+                };
+                (builtin.name.to_string(), template)
+            })
+            .collect();
+
+        if !fs::exists(templates_dir()).unwrap_or(false) {
+            return Ok(templates);
+        }
+        for file in files_in_dir(&templates_dir(), MANAGER_FILE_EXT).unwrap() {
+            let mut template: Self = toml::from_str(&get_contents_of(&file).unwrap())
+                .map_err(|err| Error::new(&format!("{}: {}", file, err), MANAGER_ERROR))?;
+            template.builtin = false;
+            templates.insert(manager_name(&file), template);
+        }
+        Ok(templates)
+    }
+
+    pub fn get(name: &str) -> Result<Self, Error> {
+        Self::catalogue()?
+            .remove(name)
+            .ok_or_else(|| Error::new(ERR_TEMPLATE_NOT_FOUND, MANAGER_ERROR))
+    }
+}
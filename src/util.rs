@@ -1,13 +1,53 @@
-use crate::error::Error;
-use std::env;
+use crate::command::display_argv;
+use crate::error::{Error, LOCK_ERROR};
+use crate::globals::{ERR_EMPTY_COMMAND, GENERATION_FILE_EXT, MANAGER_FILE_EXT};
+use serde::{Deserialize, Serialize, Serializer};
+use sha2::{Digest, Sha256};
 use std::fs;
-use std::fs::File;
-use std::fs::OpenOptions;
+use std::fs::{File, OpenOptions, TryLockError};
 use std::io;
 use std::io::Read;
 use std::io::Write;
+use std::os::unix::process::CommandExt;
 use std::path::Path;
-use std::process::{exit, Command};
+use std::process::{exit, Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+static RECORDED_COMMANDS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// How often a command with a timeout is checked for having exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long a timed out command gets to exit after SIGTERM before it is sent SIGKILL.
+const KILL_GRACE: Duration = Duration::from_secs(5);
+
+/// Where and for how long a command may run, from the settings of its manager.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RunOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub workdir: Option<String>,
+    /// Seconds after which the command's process group is killed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+}
+
+/// In dry-run mode commands are recorded instead of executed and nothing is written to disk.
+pub fn set_dry_run(dry_run: bool) {
+    DRY_RUN.store(dry_run, Ordering::Relaxed);
+}
+
+pub fn is_dry_run() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
+
+/// Commands passed to `run_command` or `command_output` during a dry run, in the order
+/// they would have run.
+pub fn recorded_commands() -> Vec<String> {
+    RECORDED_COMMANDS.lock().unwrap().clone()
+}
 
 pub fn remove_all_files_in_dir(dir: &str) -> io::Result<()> {
     for entry in fs::read_dir(dir)? {
@@ -30,29 +70,135 @@ pub fn get_filename(path: &str) -> Option<&str> {
     Path::new(path).file_name()?.to_str()
 }
 
-pub fn run_command(command_str: &str) -> io::Result<()> {
-    println!("Running: {}", command_str);
-    let shell = env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string());
+/// Name of a manager as used on the command line, derived from its file path.
+pub fn manager_name(path: &str) -> String {
+    let filename = get_filename(path).unwrap_or(path);
+    filename
+        .strip_suffix(MANAGER_FILE_EXT)
+        .unwrap_or(filename)
+        .to_string()
+}
+
+/// Serializes a manager file path as the manager's name, the one the CLI takes.
+pub fn serialize_manager_name<S: Serializer>(path: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&manager_name(path))
+}
 
-    if !Path::new(&shell).exists() {
-        eprintln!("Shell '{}' not found.", shell);
-        exit(1);
+fn command_for(argv: &[String], options: &RunOptions) -> io::Result<Command> {
+    let (program, args) = argv
+        .split_first()
+        .ok_or_else(|| io::Error::other(ERR_EMPTY_COMMAND))?;
+    let mut command = Command::new(program);
+    command.args(args);
+    if let Some(workdir) = &options.workdir {
+        command.current_dir(workdir);
     }
+    // In a process group of its own, a timeout kills whatever the command started too.
+    if options.timeout.is_some() {
+        command.process_group(0);
+    }
+    Ok(command)
+}
 
-    let mut child = Command::new(shell).arg("-c").arg(command_str).spawn()?;
+/// Waits for the command to exit. Once its timeout expires the process group is sent
+/// SIGTERM, then SIGKILL if it is still running after a grace period.
+fn wait_for(child: &mut Child, argv: &[String], options: &RunOptions) -> io::Result<ExitStatus> {
+    let Some(timeout) = options.timeout else {
+        return child.wait();
+    };
+    if wait_until(child, Instant::now() + Duration::from_secs(timeout))?.is_some() {
+        return child.wait();
+    }
+    let group = -(child.id() as libc::pid_t);
+    // SAFETY: kill has no memory safety preconditions, the group is the child's own.
+    unsafe { libc::kill(group, libc::SIGTERM) };
+    if wait_until(child, Instant::now() + KILL_GRACE)?.is_none() {
+        // SAFETY: as above.
+        unsafe { libc::kill(group, libc::SIGKILL) };
+    }
+    child.wait()?;
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!(
+            "`{}` timed out after {}s and was killed",
+            display_argv(argv),
+            timeout
+        ),
+    ))
+}
 
-    let status = child.wait()?;
+fn wait_until(child: &mut Child, deadline: Instant) -> io::Result<Option<ExitStatus>> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+pub fn run_command(argv: &[String]) -> io::Result<()> {
+    run_command_with(argv, &RunOptions::default())
+}
+
+pub fn run_command_with(argv: &[String], options: &RunOptions) -> io::Result<()> {
+    let command_str = display_argv(argv);
+    if is_dry_run() {
+        RECORDED_COMMANDS.lock().unwrap().push(command_str);
+        return Ok(());
+    }
+    println!("Running: {}", command_str);
+
+    let mut child = command_for(argv, options)?.spawn()?;
+
+    let status = wait_for(&mut child, argv, options)?;
 
     if !status.success() {
-        eprintln!("Command failed with status: {}", status);
-        if yesnoprompt("Should I exit? [Y/n]: ") {
-            exit(1);
-        }
+        return Err(io::Error::other(format!(
+            "`{}` failed with status: {}",
+            command_str, status
+        )));
     }
 
     Ok(())
 }
 
+pub fn command_output(argv: &[String]) -> io::Result<String> {
+    command_output_with(argv, &RunOptions::default())
+}
+
+/// Runs a command and returns its stdout, stderr is passed through to the terminal.
+/// A dry run records the command and returns no output.
+pub fn command_output_with(argv: &[String], options: &RunOptions) -> io::Result<String> {
+    if is_dry_run() {
+        RECORDED_COMMANDS.lock().unwrap().push(display_argv(argv));
+        return Ok(String::new());
+    }
+    let mut child = command_for(argv, options)?
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()?;
+    // Read on another thread, so a command filling the pipe can't outlast its timeout.
+    let mut stdout = child.stdout.take().unwrap();
+    let reader = thread::spawn(move || {
+        let mut output = Vec::new();
+        stdout.read_to_end(&mut output).map(|_| output)
+    });
+    let status = wait_for(&mut child, argv, options)?;
+    let output = reader.join().unwrap()?;
+
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "`{}` failed with status: {}",
+            display_argv(argv),
+            status
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output).into_owned())
+}
+
 pub fn get_contents_of(file: &str) -> io::Result<String> {
     let mut contents = String::new();
     File::open(file)?.read_to_string(&mut contents)?;
@@ -61,7 +207,7 @@ pub fn get_contents_of(file: &str) -> io::Result<String> {
 
 pub fn yesnoprompt(msg: &str) -> bool {
     let out = prompt(msg).to_lowercase();
-    return out == "y" || out.is_empty();
+    out == "y" || out.is_empty()
 }
 
 pub fn prompt(msg: &str) -> String {
@@ -104,7 +250,7 @@ pub fn terminate_on_error<T>(value: Result<T, Error>) -> T {
         eprintln!("ERROR: {}", err.msg);
         exit(err.code);
     }
-    return value.unwrap();
+    value.unwrap()
 }
 
 pub fn conf_dir() -> String {
@@ -125,6 +271,52 @@ pub fn managers_dir() -> String {
     path
 }
 
+/// Where `Generation::restore` stages the new manager files.
+pub fn staging_managers_dir() -> String {
+    let mut path = conf_dir();
+    path.push_str("/manager.new");
+    path
+}
+
+/// Where `Generation::restore` moves the replaced manager files until the swap is done.
+pub fn old_managers_dir() -> String {
+    let mut path = conf_dir();
+    path.push_str("/manager.old");
+    path
+}
+
+pub fn lock_file() -> String {
+    let mut path = conf_dir();
+    path.push_str("/kaeru.lock");
+    path
+}
+
+pub fn objects_dir() -> String {
+    let mut path = conf_dir();
+    path.push_str("/objects/");
+    path
+}
+
+pub fn object_file(hash: &str) -> String {
+    format!("{}{}", objects_dir(), hash)
+}
+
+pub fn gen_file(genid: usize) -> String {
+    format!("{}{}{}", gen_dir(), genid, GENERATION_FILE_EXT)
+}
+
+pub fn gen_state_file() -> String {
+    let mut path = conf_dir();
+    path.push_str("/gen_state.toml");
+    path
+}
+
+pub fn templates_dir() -> String {
+    let mut path = conf_dir();
+    path.push_str("/templates/");
+    path
+}
+
 pub fn gen_dir() -> String {
     let mut path = conf_dir();
     path.push_str("/gen/");
@@ -132,19 +324,40 @@ pub fn gen_dir() -> String {
 }
 
 pub fn create_file_with_contents(file_path: &str, contents: &str) {
-    let mut file = File::create(file_path).expect("Failed to create file");
-    file.write_all(contents.as_bytes())
-        .expect("Failed to write to file");
+    overwrite_contents_of(file_path, contents).expect("Failed to write to file");
 }
 
+/// Replaces the file atomically: the contents go to a temporary file next to it, which
+/// is synced and then renamed over the original, so readers see either version in full.
 pub fn overwrite_contents_of(file: &str, contents: &str) -> io::Result<()> {
-    let mut file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(file)?;
-    file.write_all(contents.as_bytes())
+    let path = Path::new(file);
+    let tmp = format!("{}.tmp-{}", file, std::process::id());
+    let mut tmpfile = File::create(&tmp)?;
+    tmpfile.write_all(contents.as_bytes())?;
+    tmpfile.sync_all()?;
+    fs::rename(&tmp, path)?;
+    sync_dir(path.parent().unwrap_or(Path::new(".")))
 }
+
+/// Writes the file only if its contents differ, returns whether it was written.
+pub fn write_if_changed(file: &str, contents: &str) -> io::Result<bool> {
+    if get_contents_of(file).is_ok_and(|current| current == contents) {
+        return Ok(false);
+    }
+    overwrite_contents_of(file, contents)?;
+    Ok(true)
+}
+
+/// Makes renames and removals in a directory durable.
+pub fn sync_dir(dir: &Path) -> io::Result<()> {
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    File::open(dir)?.sync_all()
+}
+
 pub fn mkdir_if_not_exists(dir: &str) -> io::Result<()> {
     if !fs::exists(dir)? {
         fs::create_dir_all(dir)?;
@@ -152,11 +365,66 @@ pub fn mkdir_if_not_exists(dir: &str) -> io::Result<()> {
     Ok(())
 }
 
+/// Hex encoded SHA-256 of the contents.
+pub fn content_hash(contents: &str) -> String {
+    format!("{:x}", Sha256::digest(contents.as_bytes()))
+}
+
+/// Takes the advisory lock on the config dir, it is released when the file is dropped.
+/// The holder's PID is written into the lock file so others can report it.
+pub fn acquire_lock(wait: bool) -> Result<File, Error> {
+    let path = lock_file();
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(&path)?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            let holder = get_contents_of(&path).unwrap_or_default();
+            let holder = holder.trim();
+            if !wait {
+                return Err(Error::new(
+                    &format!(
+                        "Another kaeru (PID {}) holds the lock on {}, use --wait to wait for it",
+                        holder, path
+                    ),
+                    LOCK_ERROR,
+                ));
+            }
+            eprintln!("Waiting for kaeru (PID {}) to release the lock...", holder);
+            file.lock()?;
+        }
+        Err(TryLockError::Error(err)) => return Err(err.into()),
+    }
+    file.set_len(0)?;
+    file.write_all(std::process::id().to_string().as_bytes())?;
+    Ok(file)
+}
+
 pub fn epoch_time_secs() -> i64 {
     let now = chrono::Utc::now();
     now.timestamp()
 }
 
+/// Parses durations like "90s", "12h", "30d" or "2w" into seconds.
+pub fn parse_duration(duration: &str) -> Option<i64> {
+    let duration = duration.trim();
+    let unit = duration.chars().last()?;
+    let amount: i64 = duration[..duration.len() - unit.len_utf8()].parse().ok()?;
+    let secs = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    amount.checked_mul(secs)
+}
+
 pub fn epoch_to_str(epoch_seconds: i64) -> String {
     let naive_datetime = chrono::DateTime::from_timestamp(epoch_seconds, 0);
     naive_datetime